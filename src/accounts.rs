@@ -0,0 +1,123 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use parking_lot::RwLock;
+use proc_qq::re_exports::ricq::msg::MessageChain;
+
+use crate::client::Client;
+
+/// The QQ accounts the bridge runs, and the groups each of them is a member of.
+///
+/// Every group is served by one designated account: the online member with the smallest uin. Only
+/// it handles the group's messages, so groups shared by several accounts are not bridged twice,
+/// and it is the one that sends forwarded messages to the group.
+#[derive(Debug, Clone, Default)]
+pub struct Accounts(Arc<RwLock<BTreeMap<i64, Account>>>);
+
+struct Account {
+    client: Client,
+    online: bool,
+    groups: HashSet<i64>,
+}
+
+impl std::fmt::Debug for Account {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Account")
+            .field("online", &self.online)
+            .field("groups", &self.groups)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Accounts {
+    pub fn set_online(&self, uin: i64, client: Client) {
+        let mut accounts = self.0.write();
+        let account = accounts.entry(uin).or_insert_with(|| Account {
+            client: client.clone(),
+            online: true,
+            groups: HashSet::new(),
+        });
+        account.client = client;
+        account.online = true;
+    }
+    pub fn set_offline(&self, uin: i64) {
+        if let Some(account) = self.0.write().get_mut(&uin) {
+            account.online = false;
+        }
+    }
+    /// Reload the group list of an account from QQ.
+    pub async fn refresh_groups(&self, uin: i64) -> Result<usize> {
+        let client = self
+            .client(uin)
+            .ok_or_else(|| anyhow!("account {} is not logged in", uin))?;
+        let groups: HashSet<_> = client
+            .group_list()
+            .await?
+            .into_iter()
+            .map(|(code, _)| code)
+            .collect();
+        let count = groups.len();
+        if let Some(account) = self.0.write().get_mut(&uin) {
+            account.groups = groups;
+        }
+        Ok(count)
+    }
+    pub fn joined(&self, uin: i64, group: i64) {
+        if let Some(account) = self.0.write().get_mut(&uin) {
+            account.groups.insert(group);
+        }
+    }
+    pub fn left(&self, uin: i64, group: i64) {
+        if let Some(account) = self.0.write().get_mut(&uin) {
+            account.groups.remove(&group);
+        }
+    }
+    /// Forget a group for every account, e.g. when it is disbanded.
+    pub fn forget_group(&self, group: i64) {
+        for account in self.0.write().values_mut() {
+            account.groups.remove(&group);
+        }
+    }
+    /// Whether the uin belongs to one of our accounts.
+    pub fn is_own(&self, uin: i64) -> bool {
+        self.0.read().contains_key(&uin)
+    }
+    /// The account that serves the group, if any online account is a member.
+    pub fn designated(&self, group: i64) -> Option<i64> {
+        self.0
+            .read()
+            .iter()
+            .find(|(_, account)| account.online && account.groups.contains(&group))
+            .map(|(uin, _)| *uin)
+    }
+    /// The client of the account that serves the group.
+    pub fn client_for(&self, group: i64) -> Option<(i64, Client)> {
+        let uin = self.designated(group)?;
+        self.client(uin).map(|client| (uin, client))
+    }
+    /// Send a message to a group through the account that serves it.
+    pub async fn send_group_message(&self, group: i64, message: MessageChain) -> Result<()> {
+        let (_, client) = self
+            .client_for(group)
+            .ok_or_else(|| anyhow!("no online account is a member of group {}", group))?;
+        client.send_group_message(group, message).await?;
+        Ok(())
+    }
+    pub fn client(&self, uin: i64) -> Option<Client> {
+        self.0
+            .read()
+            .get(&uin)
+            .filter(|account| account.online)
+            .map(|account| account.client.clone())
+    }
+    /// Clients of all online accounts.
+    pub fn clients(&self) -> Vec<Client> {
+        self.0
+            .read()
+            .values()
+            .filter(|account| account.online)
+            .map(|account| account.client.clone())
+            .collect()
+    }
+}
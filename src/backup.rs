@@ -0,0 +1,277 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{Cluster, Group, DB};
+
+/// Version of the backup document format written by this version of the bridge.
+const BACKUP_VERSION: u32 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// Guess the format from a file extension, defaulting to JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+}
+
+/// All clusters of a deployment.
+///
+/// Clusters and their members are sorted, so that exports of unchanged clusters are identical and
+/// can be kept in version control.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub clusters: Vec<ClusterBackup>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClusterBackup {
+    pub name: String,
+    #[serde(default)]
+    pub owner: Option<i64>,
+    #[serde(default)]
+    pub admins: BTreeSet<i64>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+}
+
+impl From<Cluster> for ClusterBackup {
+    fn from(cluster: Cluster) -> Self {
+        let mut groups: Vec<_> = cluster.groups.into_iter().collect();
+        groups.sort_by(|a, b| (a.im.as_str(), &a.id).cmp(&(b.im.as_str(), &b.id)));
+        Self {
+            name: cluster.name,
+            owner: cluster.owner,
+            admins: cluster.admins.into_iter().collect(),
+            groups,
+        }
+    }
+}
+
+impl From<ClusterBackup> for Cluster {
+    fn from(cluster: ClusterBackup) -> Self {
+        Self {
+            name: cluster.name,
+            groups: cluster.groups.into_iter().collect(),
+            owner: cluster.owner,
+            admins: cluster.admins.into_iter().collect(),
+        }
+    }
+}
+
+impl Backup {
+    pub fn render(&self, format: Format) -> Result<String> {
+        Ok(match format {
+            Format::Json => serde_json::to_string_pretty(self)?,
+            Format::Yaml => serde_yaml::to_string(self)?,
+        })
+    }
+    /// Parse a backup in either format. YAML is a superset of JSON, so one parser reads both.
+    pub fn parse(text: &str) -> Result<Self> {
+        let backup: Self = serde_yaml::from_str(text).context("invalid backup document")?;
+        if backup.version > BACKUP_VERSION {
+            bail!(
+                "Backup has version {}, but this version of the bridge only supports up to {}.",
+                backup.version,
+                BACKUP_VERSION
+            );
+        }
+        backup.check_names()?;
+        Ok(backup)
+    }
+    fn check_names(&self) -> Result<()> {
+        let invalid: Vec<_> = self
+            .clusters
+            .iter()
+            .map(|cluster| cluster.name.as_str())
+            .filter(|name| !Cluster::is_valid_name(name))
+            .map(|name| format!("{:?}", name))
+            .collect();
+        if !invalid.is_empty() {
+            bail!(
+                "Invalid cluster names: {}. Names may only contain letters, digits, '-' and '_'.",
+                invalid.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of an import.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Imported {
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+}
+
+impl fmt::Display for Imported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} created, {} updated, {} unchanged",
+            self.created, self.updated, self.unchanged
+        )
+    }
+}
+
+/// Context of an import error. Clusters are imported one at a time, so those before the one that
+/// failed stay imported.
+#[derive(Debug)]
+pub struct PartialImport {
+    pub cluster: String,
+    pub imported: Imported,
+}
+
+impl fmt::Display for PartialImport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to import cluster {}, after {}",
+            self.cluster, self.imported
+        )
+    }
+}
+
+pub async fn export(db: &DB) -> Result<Backup> {
+    let mut clusters: Vec<ClusterBackup> = db
+        .all_clusters()
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    clusters.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Backup {
+        version: BACKUP_VERSION,
+        clusters,
+    })
+}
+
+/// Restore the clusters of a backup, replacing existing clusters of the same name. Clusters that
+/// are not in the backup are left alone, so importing the same backup twice changes nothing.
+///
+/// Nothing is written if any cluster name is invalid. Other errors carry a [`PartialImport`]
+/// context telling what was imported before.
+pub async fn import(db: &DB, backup: Backup) -> Result<Imported> {
+    backup.check_names()?;
+    let mut imported = Imported::default();
+    for cluster in backup.clusters {
+        let name = cluster.name.clone();
+        let existing = import_cluster(db, cluster)
+            .await
+            .with_context(|| PartialImport {
+                cluster: name,
+                imported,
+            })?;
+        match existing {
+            Some(true) => imported.unchanged += 1,
+            Some(false) => imported.updated += 1,
+            None => imported.created += 1,
+        }
+    }
+    Ok(imported)
+}
+
+/// Import a single cluster, returning whether an existing one of the same name was already
+/// identical, or `None` if there was none.
+async fn import_cluster(db: &DB, cluster: ClusterBackup) -> Result<Option<bool>> {
+    let existing = db.cluster(&cluster.name).await?.map(ClusterBackup::from);
+    let mut wanted = Cluster::from(cluster);
+    if let Some(owner) = wanted.owner {
+        wanted.admins.remove(&owner);
+    }
+    let unchanged = existing.map(|existing| existing == ClusterBackup::from(wanted.clone()));
+    if unchanged != Some(true) {
+        db.put_cluster(&wanted).await?;
+    }
+    Ok(unchanged)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    use super::{export, import, Backup, ClusterBackup, Format, Imported};
+    use crate::db::{ClusterStore, Group, MemoryStore, DB};
+
+    #[tokio::test]
+    async fn import_of_export_is_idempotent() {
+        let source: DB = Arc::new(MemoryStore::default());
+        let name = source.new_cluster(Some(1)).await.unwrap();
+        source.add_admin(&name, 2).await.unwrap();
+        source.join(&name, &Group::from_qq(100)).await.unwrap();
+        source.join(&name, &Group::from_qq(200)).await.unwrap();
+        let text = export(&source).await.unwrap().render(Format::Yaml).unwrap();
+
+        let target: DB = Arc::new(MemoryStore::default());
+        let backup = Backup::parse(&text).unwrap();
+        let first = import(&target, backup.clone()).await.unwrap();
+        assert_eq!(
+            first,
+            Imported {
+                created: 1,
+                ..Imported::default()
+            }
+        );
+        let second = import(&target, backup).await.unwrap();
+        assert_eq!(
+            second,
+            Imported {
+                unchanged: 1,
+                ..Imported::default()
+            }
+        );
+        assert_eq!(
+            export(&target).await.unwrap(),
+            export(&source).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_names_are_rejected_before_writing() {
+        let text = r#"{"version": 1, "clusters": [{"name": "good"}, {"name": "bad/name"}]}"#;
+        let e = Backup::parse(text).unwrap_err();
+        assert!(e.to_string().contains("\"bad/name\""), "{}", e);
+
+        let backup = Backup {
+            version: 1,
+            clusters: vec![
+                ClusterBackup {
+                    name: "good".to_string(),
+                    owner: None,
+                    admins: BTreeSet::new(),
+                    groups: Vec::new(),
+                },
+                ClusterBackup {
+                    name: String::new(),
+                    owner: None,
+                    admins: BTreeSet::new(),
+                    groups: Vec::new(),
+                },
+            ],
+        };
+        let target: DB = Arc::new(MemoryStore::default());
+        assert!(import(&target, backup).await.is_err());
+        assert!(target.clusters().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn newer_backups_are_rejected() {
+        assert!(Backup::parse(r#"{"version": 99, "clusters": []}"#).is_err());
+        assert!(Backup::parse(r#"{"version": 1, "clusters": []}"#).is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use proc_qq::MessageChainParseTrait;
+use tracing::error;
+
+use crate::accounts::Accounts;
+use crate::db::{Group, DB, IM};
+use crate::metrics;
+use crate::status::ForwardStats;
+
+/// A message on its way from one IM to another, reduced to what every IM can render.
+#[derive(Debug, Clone)]
+pub struct BridgedMessage {
+    pub source: Group,
+    /// Identifier of the sender, unique within the IM of the source.
+    pub sender_id: String,
+    /// Display name of the sender.
+    pub sender: String,
+    /// URL of the sender's avatar, for IMs that can show one per message.
+    pub avatar: Option<String>,
+    pub text: String,
+}
+
+/// Connection to an IM other than QQ, which is served by [`Accounts`].
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Deliver a bridged message to a group of this IM.
+    async fn send(&self, group: &str, message: &BridgedMessage) -> Result<()>;
+    /// Post a message of the bridge itself, like a notice about cluster changes.
+    async fn send_notice(&self, group: &str, text: &str) -> Result<()>;
+}
+
+/// The backends enabled in the config, by the IM they serve.
+#[derive(Clone, Default)]
+pub struct Backends(Arc<HashMap<IM, Arc<dyn Backend>>>);
+
+impl Backends {
+    pub fn new(backends: HashMap<IM, Arc<dyn Backend>>) -> Self {
+        Self(Arc::new(backends))
+    }
+    pub fn get(&self, im: &IM) -> Result<&Arc<dyn Backend>> {
+        self.0
+            .get(im)
+            .ok_or_else(|| anyhow!("{} is not enabled", im.as_str()))
+    }
+    /// Post a notice to a group of any IM.
+    pub async fn send_notice(&self, accounts: &Accounts, group: &Group, text: &str) -> Result<()> {
+        match group.im {
+            IM::QQ => {
+                accounts
+                    .send_group_message(group.id.parse()?, text.parse_message_chain())
+                    .await
+            }
+            _ => self.get(&group.im)?.send_notice(&group.id, text).await,
+        }
+    }
+}
+
+/// Forwards messages received by backends to the other groups of their clusters.
+///
+/// Messages received by QQ accounts take the forwarder handler instead, which keeps images and
+/// other QQ elements between QQ groups.
+#[derive(Clone)]
+pub struct Relay {
+    pub db: DB,
+    pub accounts: Accounts,
+    pub backends: Backends,
+    pub stats: ForwardStats,
+}
+
+impl Relay {
+    pub async fn relay(&self, message: BridgedMessage) {
+        let targets = {
+            let _timer = metrics::FORWARD_TARGETS_QUERY.start_timer();
+            match self.db.forward_targets(&message.source).await {
+                Ok(targets) => targets,
+                Err(e) => {
+                    error!(?e, source = ?message.source, "failed to look up forward targets");
+                    return;
+                }
+            }
+        };
+        for target in targets {
+            let relay = self.clone();
+            let message = message.clone();
+            tokio::spawn(async move {
+                let timer = metrics::FORWARD_LATENCY.start_timer();
+                let result = relay.deliver(&target, &message).await;
+                timer.observe_duration();
+                match result {
+                    Ok(()) => {
+                        relay.stats.record_delivered();
+                        metrics::MESSAGES_FORWARDED
+                            .with_label_values(&[format!("{:?}", target.im).as_str()])
+                            .inc();
+                    }
+                    Err(e) => {
+                        error!(?e, source = ?message.source, "failed to forward message");
+                        metrics::FORWARD_FAILURES
+                            .with_label_values(&[metrics::error_class(&e).as_str()])
+                            .inc();
+                        relay.stats.record_failure(message.source, target, &e);
+                    }
+                }
+            });
+        }
+    }
+    async fn deliver(&self, target: &Group, message: &BridgedMessage) -> Result<()> {
+        match target.im {
+            IM::QQ => {
+                let text = format!("{}: {}", message.sender, message.text);
+                self.accounts
+                    .send_group_message(target.id.parse()?, text.parse_message_chain())
+                    .await
+            }
+            _ => {
+                self.backends
+                    .get(&target.im)?
+                    .send(&target.id, message)
+                    .await
+            }
+        }
+    }
+}
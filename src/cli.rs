@@ -0,0 +1,183 @@
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use itertools::Itertools;
+
+use crate::backup::{self, Backup, Format};
+use crate::db::{Group, DB, IM};
+use crate::handlers::auth::{Token, OTP};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file. Environment variables take precedence over it.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Run the bot if omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the bot.
+    Run,
+    #[command(flatten)]
+    Offline(OfflineCommand),
+}
+
+/// Commands working directly on the storage, without logging into QQ.
+#[derive(Debug, Subcommand)]
+pub enum OfflineCommand {
+    /// Manage clusters.
+    ///
+    /// A running bot picks up the changes right away if the storage is a MongoDB replica set, and
+    /// within a minute otherwise.
+    Cluster {
+        #[command(subcommand)]
+        cmd: ClusterCommand,
+    },
+    /// Manage one-time passwords for joining groups to clusters.
+    Otp {
+        #[command(subcommand)]
+        cmd: OtpCommand,
+    },
+    /// Manage the management token.
+    Token {
+        #[command(subcommand)]
+        cmd: TokenCommand,
+    },
+    /// Write all clusters to a backup document.
+    Export {
+        /// Output file. Defaults to stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Defaults to the one matching the extension of the output file, or JSON.
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+    },
+    /// Restore clusters from a backup document in either format.
+    ///
+    /// Clusters in the backup replace existing ones of the same name. Others are left alone. A
+    /// running bot picks up the changes right away if the storage is a MongoDB replica set, and
+    /// within a minute otherwise.
+    Import {
+        /// Input file. Defaults to stdin.
+        input: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ClusterCommand {
+    /// List all clusters.
+    List,
+    /// Create a new cluster, printing its name.
+    Add {
+        /// QQ uin of the owner.
+        #[arg(short, long)]
+        owner: Option<i64>,
+    },
+    /// Delete a cluster.
+    Delete { cluster: String },
+    /// Show owner, admins and groups of a cluster.
+    Show { cluster: String },
+    /// Add a group to a cluster, e.g. a Discord channel, which can not join by command.
+    Join { cluster: String, im: IM, id: String },
+    /// Remove a group from a cluster.
+    Leave { cluster: String, im: IM, id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum OtpCommand {
+    /// Issue a one-time password, usable with a running bot.
+    Issue,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TokenCommand {
+    /// Replace the management token, printing the new one.
+    ///
+    /// A running bot keeps accepting the old token, and only the old one, until it restarts.
+    Rotate,
+}
+
+impl OfflineCommand {
+    pub async fn run(self, db: DB) -> Result<()> {
+        match self {
+            Self::Cluster { cmd } => cmd.run(db).await?,
+            Self::Otp {
+                cmd: OtpCommand::Issue,
+            } => println!("{}", OTP::new(db).generate_new().await?),
+            Self::Token {
+                cmd: TokenCommand::Rotate,
+            } => {
+                println!("{}", Token::rotate(&db).await?);
+                // Not on stdout, which scripts may read the token from.
+                eprintln!(
+                    "Restart running bots, which keep accepting only the old token until then."
+                );
+            }
+            Self::Export { output, format } => {
+                let format = format
+                    .or_else(|| output.as_deref().map(Format::from_path))
+                    .unwrap_or(Format::Json);
+                let text = backup::export(&db).await?.render(format)?;
+                match output {
+                    Some(path) => std::fs::write(&path, text)
+                        .with_context(|| format!("failed to write {}", path.display()))?,
+                    None => print!("{}", text),
+                }
+            }
+            Self::Import { input } => {
+                let text = match input {
+                    Some(path) => std::fs::read_to_string(&path)
+                        .with_context(|| format!("failed to read {}", path.display()))?,
+                    None => {
+                        let mut text = String::new();
+                        std::io::stdin().read_to_string(&mut text)?;
+                        text
+                    }
+                };
+                let imported = backup::import(&db, Backup::parse(&text)?).await?;
+                println!("{}", imported);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ClusterCommand {
+    async fn run(self, db: DB) -> Result<()> {
+        match self {
+            Self::List => {
+                for name in db.clusters().await?.iter().sorted() {
+                    println!("{}", name);
+                }
+            }
+            Self::Add { owner } => println!("{}", db.new_cluster(owner).await?),
+            Self::Delete { cluster } => db.delete_cluster(&cluster).await?,
+            Self::Join { cluster, im, id } => db.join(&cluster, &Group { im, id }).await?,
+            Self::Leave { cluster, im, id } => db.leave(&cluster, &Group { im, id }).await?,
+            Self::Show { cluster } => {
+                let Some(found) = db.cluster(&cluster).await? else {
+                    bail!("No such cluster.");
+                };
+                println!("Cluster: {}", found.name);
+                println!(
+                    "Owner: {}",
+                    found
+                        .owner
+                        .map_or_else(|| "(none)".to_string(), |o| o.to_string())
+                );
+                println!("Admins: {}", found.admins.iter().sorted().join(", "));
+                println!("Groups:");
+                for group in &found.groups {
+                    println!("{:?} {}", group.im, group.id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use proc_qq::re_exports::ricq;
+use proc_qq::re_exports::ricq::msg::MessageChain;
+use proc_qq::re_exports::ricq::structs::MessageReceipt;
+use proc_qq::{FriendMessageEvent, GroupMessageEvent};
+
+/// What the handlers do with a QQ account.
+///
+/// Handlers must never touch the ricq client of an event directly: events of OneBot accounts carry
+/// a placeholder that is not connected to anything. They take the `Client` inserted next to every
+/// event instead, which also lets tests substitute a fake one.
+#[async_trait]
+pub trait QQClient: Send + Sync {
+    async fn uin(&self) -> i64;
+    async fn send_group_message(&self, group: i64, message: MessageChain)
+        -> Result<MessageReceipt>;
+    async fn send_friend_message(&self, uin: i64, message: MessageChain) -> Result<()>;
+    /// Owner and admins of a group.
+    async fn group_admins(&self, group: i64) -> Result<Vec<i64>>;
+    /// How a group member is shown: their group card and nickname, or just the nickname.
+    async fn member_name(&self, group: i64, uin: i64) -> Result<String>;
+    /// Codes and names of the groups the account is in.
+    async fn group_list(&self) -> Result<Vec<(i64, String)>>;
+    async fn solve_friend_request(&self, msg_seq: i64, uin: i64, accept: bool) -> Result<()>;
+}
+
+pub type Client = Arc<dyn QQClient>;
+
+#[async_trait]
+impl QQClient for ricq::Client {
+    async fn uin(&self) -> i64 {
+        Self::uin(self).await
+    }
+    async fn send_group_message(
+        &self,
+        group: i64,
+        message: MessageChain,
+    ) -> Result<MessageReceipt> {
+        Ok(Self::send_group_message(self, group, message).await?)
+    }
+    async fn send_friend_message(&self, uin: i64, message: MessageChain) -> Result<()> {
+        Self::send_friend_message(self, uin, message).await?;
+        Ok(())
+    }
+    async fn group_admins(&self, group: i64) -> Result<Vec<i64>> {
+        Ok(self
+            .get_group_admin_list(group)
+            .await?
+            .into_keys()
+            .collect())
+    }
+    async fn member_name(&self, group: i64, uin: i64) -> Result<String> {
+        let member = self.get_group_member_info(group, uin).await?;
+        Ok(if member.card_name.is_empty() {
+            member.nickname
+        } else {
+            format!("{} ({})", member.card_name, member.nickname)
+        })
+    }
+    async fn group_list(&self) -> Result<Vec<(i64, String)>> {
+        Ok(self
+            .get_group_list()
+            .await?
+            .into_iter()
+            .map(|group| (group.code, group.name))
+            .collect())
+    }
+    async fn solve_friend_request(&self, msg_seq: i64, uin: i64, accept: bool) -> Result<()> {
+        Ok(self
+            .solve_friend_system_message(msg_seq, uin, accept)
+            .await?)
+    }
+}
+
+/// Reply to where a message came from, through the given client.
+#[async_trait]
+pub trait Reply {
+    async fn reply(&self, client: &Client, message: MessageChain) -> Result<()>;
+}
+
+#[async_trait]
+impl Reply for FriendMessageEvent {
+    async fn reply(&self, client: &Client, message: MessageChain) -> Result<()> {
+        client
+            .send_friend_message(self.inner.from_uin, message)
+            .await
+    }
+}
+
+#[async_trait]
+impl Reply for GroupMessageEvent {
+    async fn reply(&self, client: &Client, message: MessageChain) -> Result<()> {
+        client
+            .send_group_message(self.inner.group_code, message)
+            .await?;
+        Ok(())
+    }
+}
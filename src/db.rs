@@ -1,17 +1,60 @@
 use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::{bail, Result};
+use async_trait::async_trait;
 use chbs::prelude::WordProvider;
 use chbs::word::{WordList, WordSampler};
-use mongodb::bson::{doc, Bson};
-use mongodb::options::{IndexOptions, UpdateModifications};
-use mongodb::{bson, Collection, IndexModel};
+use futures::stream::BoxStream;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+use crate::config::{Config, Storage};
+pub use crate::db::memory::MemoryStore;
+pub use crate::db::mongo::MongoStore;
+pub use crate::db::routing::RoutingCache;
+pub use crate::db::sqlite::SqliteStore;
+
+mod memory;
+mod mongo;
+mod routing;
+mod sqlite;
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum IM {
     QQ,
+    /// Groups are channels, identified by their snowflake.
+    Discord,
+    /// Groups are rooms, identified by their room id.
+    Matrix,
+    /// Groups are channels, identified as `network/#channel` by the network's name in the config.
+    Irc,
+}
+
+impl IM {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::QQ => "QQ",
+            Self::Discord => "Discord",
+            Self::Matrix => "Matrix",
+            Self::Irc => "IRC",
+        }
+    }
+}
+
+impl FromStr for IM {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "qq" => Ok(Self::QQ),
+            "discord" => Ok(Self::Discord),
+            "matrix" => Ok(Self::Matrix),
+            "irc" => Ok(Self::Irc),
+            _ => bail!("Unknown IM: {}", s),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -36,131 +79,123 @@ impl Group {
     }
 }
 
+/// A set of groups whose messages are forwarded to each other.
+///
+/// Stored documents are read back with this type, so a new field needs `#[serde(default)]`, or a
+/// migration in each store if the default is not right for existing clusters.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cluster {
     pub name: String,
+    #[serde(default)]
     pub groups: HashSet<Group>,
+    /// QQ uin of the user who created the cluster. Clusters created before owners were recorded
+    /// have none.
+    #[serde(default)]
+    pub owner: Option<i64>,
+    /// QQ uins of users the owner delegated administration of this cluster to.
+    #[serde(default)]
+    pub admins: HashSet<i64>,
 }
 
-#[derive(Debug, Clone)]
-pub struct DB {
-    pub clusters: Collection<Cluster>,
+/// Privilege of a user over a cluster, in ascending order.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ClusterRole {
+    Admin,
+    Owner,
+    /// Holder of the management token.
+    Operator,
 }
 
-impl DB {
-    pub async fn connect(uri: &str, db: &str) -> Result<Self> {
-        let client = mongodb::Client::with_uri_str(uri).await?;
-        let db = client.database(db);
-        let clusters = db.collection("clusters");
-        clusters
-            .create_index(
-                IndexModel::builder()
-                    .keys(doc! {
-                        "name": 1
-                    })
-                    .options(IndexOptions::builder().unique(true).build())
-                    .build(),
-                None,
-            )
-            .await?;
-        Ok(Self { clusters })
+impl Cluster {
+    /// Whether a name is fit for a cluster: letters, digits, `-` and `_` only, so that it can be
+    /// typed in commands and used in URLs as is.
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
     }
-    pub async fn new_cluster(&self) -> Result<String> {
-        static SAMPLER: Lazy<WordSampler> = Lazy::new(|| WordList::builtin_eff_short().sampler());
-        let name = SAMPLER.word();
-        let cluster = Cluster {
-            name: name.clone(),
-            groups: Default::default(),
-        };
-        self.clusters.insert_one(cluster, None).await?;
-        Ok(name)
+    pub fn role_of(&self, uin: i64) -> Option<ClusterRole> {
+        if self.owner == Some(uin) {
+            Some(ClusterRole::Owner)
+        } else if self.admins.contains(&uin) {
+            Some(ClusterRole::Admin)
+        } else {
+            None
+        }
     }
-    pub async fn clusters(&self) -> Result<impl Iterator<Item = String>> {
-        Ok(self
-            .clusters
-            .distinct("name", None, None)
-            .await?
-            .into_iter()
-            .map(|doc| {
-                if let Bson::String(s) = doc {
-                    s
-                } else {
-                    unreachable!()
-                }
-            }))
+    /// Users responsible for this cluster: the owner followed by the admins.
+    pub fn managers(&self) -> impl Iterator<Item = i64> + '_ {
+        self.owner.into_iter().chain(self.admins.iter().copied())
     }
-    pub async fn join(&self, cluster: &str, group: &Group) -> Result<()> {
-        let group = bson::to_document(group)?;
-        let result = self
-            .clusters
-            .update_one(
-                doc! {
-                    "name": {
-                        "$eq": cluster
-                    }
-                },
-                UpdateModifications::Document(doc! {
-                    "$addToSet": {
-                        "groups": group
-                    }
-                }),
-                None,
-            )
-            .await?;
-        if result.modified_count == 0 {
-            bail!("No cluster modified.");
-        }
-        Ok(())
+}
+
+/// A change to the stored clusters, as told by [`ClusterStore::watch`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ClusterChange {
+    /// The cluster of this name was created, changed or deleted.
+    Cluster(String),
+    /// Which clusters changed is not known, e.g. for a deletion or a rename.
+    Any,
+}
+
+/// Persistent storage of clusters and of the credentials for managing them.
+///
+/// Mutations of a cluster that does not exist fail, and so do `join` and `leave` if they would not
+/// change the cluster.
+#[async_trait]
+pub trait ClusterStore: Send + Sync {
+    /// Check that the storage is reachable.
+    async fn ping(&self) -> Result<()>;
+    /// Create a cluster with a random name, returning the name.
+    async fn new_cluster(&self, owner: Option<i64>) -> Result<String>;
+    /// Names of all clusters.
+    async fn clusters(&self) -> Result<Vec<String>>;
+    async fn all_clusters(&self) -> Result<Vec<Cluster>>;
+    async fn cluster(&self, name: &str) -> Result<Option<Cluster>>;
+    async fn rename_cluster(&self, cluster: &str, new_name: &str) -> Result<()>;
+    async fn delete_cluster(&self, cluster: &str) -> Result<()>;
+    /// Create the cluster, or replace owner, admins and groups of the existing one of that name.
+    async fn put_cluster(&self, cluster: &Cluster) -> Result<()>;
+    async fn join(&self, cluster: &str, group: &Group) -> Result<()>;
+    async fn leave(&self, cluster: &str, group: &Group) -> Result<()>;
+    /// Make `owner` the owner of the cluster. The new owner stops being an admin.
+    async fn set_owner(&self, cluster: &str, owner: i64) -> Result<()>;
+    async fn add_admin(&self, cluster: &str, uin: i64) -> Result<()>;
+    async fn remove_admin(&self, cluster: &str, uin: i64) -> Result<()>;
+    /// Groups sharing a cluster with the given group, excluding the group itself.
+    async fn forward_targets(&self, group: &Group) -> Result<Vec<Group>>;
+    /// The management token, if one was saved.
+    async fn token(&self) -> Result<Option<String>>;
+    async fn set_token(&self, token: &str) -> Result<()>;
+    /// Save a one-time password until it is used.
+    async fn add_otp(&self, otp: &str) -> Result<()>;
+    /// Remove a one-time password, returning whether it was there.
+    async fn take_otp(&self, otp: &str) -> Result<bool>;
+    /// Notifications of changes to any cluster, including those made by other instances sharing
+    /// the storage. `None` if the storage can not be shared.
+    async fn watch(&self) -> Result<Option<BoxStream<'static, Result<ClusterChange>>>> {
+        Ok(None)
     }
-    pub async fn forward_targets(&self, group: &Group) -> Result<Vec<Group>> {
-        #[derive(Debug, Deserialize)]
-        struct Targets {
-            targets: Vec<Group>,
+}
+
+pub type DB = Arc<dyn ClusterStore>;
+
+/// Open the storage backend selected in the config, with a routing table in front of it.
+///
+/// The table only follows changes made by others once [`RoutingCache::watch`] is called.
+pub async fn connect(config: &Config) -> Result<Arc<RoutingCache>> {
+    let store: DB = match config.storage {
+        Storage::MongoDB => {
+            Arc::new(MongoStore::connect(&config.mongodb.uri, &config.mongodb.database).await?)
         }
-        let group = bson::to_document(group)?;
-        let mut cursor = self
-            .clusters
-            .aggregate(
-                [
-                    doc! {
-                        "$match": doc! {
-                            "groups": doc! {
-                                "$all": [
-                                    &group
-                                ]
-                            }
-                        }
-                    },
-                    doc! {
-                        "$unwind": doc! {
-                            "path": "$groups"
-                        }
-                    },
-                    doc! {
-                        "$match": doc! {
-                            "groups": doc! {
-                                "$ne": group
-                            }
-                        }
-                    },
-                    doc! {
-                        "$group": doc! {
-                            "_id": Bson::Null,
-                            "targets": doc! {
-                                "$addToSet": "$groups"
-                            }
-                        }
-                    },
-                ],
-                None,
-            )
-            .await?
-            .with_type::<Targets>();
-        Ok(if cursor.advance().await? {
-            let targets = cursor.deserialize_current()?;
-            targets.targets
-        } else {
-            vec![]
-        })
-    }
+        Storage::SQLite => Arc::new(SqliteStore::open(&config.sqlite.path).await?),
+        Storage::Memory => Arc::new(MemoryStore::default()),
+    };
+    Ok(Arc::new(RoutingCache::new(store).await?))
+}
+
+fn random_cluster_name() -> String {
+    static SAMPLER: Lazy<WordSampler> = Lazy::new(|| WordList::builtin_eff_short().sampler());
+    SAMPLER.word()
 }
@@ -0,0 +1,145 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use parking_lot::RwLock;
+
+use crate::db::{random_cluster_name, Cluster, ClusterStore, Group};
+
+/// Storage that lives and dies with the process. For tests and trying things out.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    clusters: Arc<RwLock<HashMap<String, Cluster>>>,
+    token: Arc<RwLock<Option<String>>>,
+    otps: Arc<RwLock<HashSet<String>>>,
+}
+
+impl MemoryStore {
+    fn update<T>(&self, cluster: &str, f: impl FnOnce(&mut Cluster) -> Result<T>) -> Result<T> {
+        match self.clusters.write().get_mut(cluster) {
+            Some(found) => f(found),
+            None => bail!("No such cluster."),
+        }
+    }
+}
+
+#[async_trait]
+impl ClusterStore for MemoryStore {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+    async fn new_cluster(&self, owner: Option<i64>) -> Result<String> {
+        let name = random_cluster_name();
+        let mut clusters = self.clusters.write();
+        if clusters.contains_key(&name) {
+            bail!("Cluster {} already exists.", name);
+        }
+        clusters.insert(
+            name.clone(),
+            Cluster {
+                name: name.clone(),
+                groups: Default::default(),
+                owner,
+                admins: Default::default(),
+            },
+        );
+        Ok(name)
+    }
+    async fn clusters(&self) -> Result<Vec<String>> {
+        Ok(self.clusters.read().keys().cloned().collect())
+    }
+    async fn all_clusters(&self) -> Result<Vec<Cluster>> {
+        Ok(self.clusters.read().values().cloned().collect())
+    }
+    async fn cluster(&self, name: &str) -> Result<Option<Cluster>> {
+        Ok(self.clusters.read().get(name).cloned())
+    }
+    async fn rename_cluster(&self, cluster: &str, new_name: &str) -> Result<()> {
+        let mut clusters = self.clusters.write();
+        if clusters.contains_key(new_name) {
+            bail!("Cluster {} already exists.", new_name);
+        }
+        let Some(mut found) = clusters.remove(cluster) else {
+            bail!("No such cluster.");
+        };
+        found.name = new_name.to_string();
+        clusters.insert(found.name.clone(), found);
+        Ok(())
+    }
+    async fn delete_cluster(&self, cluster: &str) -> Result<()> {
+        if self.clusters.write().remove(cluster).is_none() {
+            bail!("No cluster deleted.");
+        }
+        Ok(())
+    }
+    async fn put_cluster(&self, cluster: &Cluster) -> Result<()> {
+        self.clusters
+            .write()
+            .insert(cluster.name.clone(), cluster.clone());
+        Ok(())
+    }
+    async fn join(&self, cluster: &str, group: &Group) -> Result<()> {
+        self.update(cluster, |found| {
+            if !found.groups.insert(group.clone()) {
+                bail!("No cluster modified.");
+            }
+            Ok(())
+        })
+    }
+    async fn leave(&self, cluster: &str, group: &Group) -> Result<()> {
+        self.update(cluster, |found| {
+            if !found.groups.remove(group) {
+                bail!("Group is not in the cluster.");
+            }
+            Ok(())
+        })
+    }
+    async fn set_owner(&self, cluster: &str, owner: i64) -> Result<()> {
+        self.update(cluster, |found| {
+            found.owner = Some(owner);
+            found.admins.remove(&owner);
+            Ok(())
+        })
+    }
+    async fn add_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.update(cluster, |found| {
+            found.admins.insert(uin);
+            Ok(())
+        })
+    }
+    async fn remove_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.update(cluster, |found| {
+            found.admins.remove(&uin);
+            Ok(())
+        })
+    }
+    async fn token(&self) -> Result<Option<String>> {
+        Ok(self.token.read().clone())
+    }
+    async fn set_token(&self, token: &str) -> Result<()> {
+        *self.token.write() = Some(token.to_string());
+        Ok(())
+    }
+    async fn add_otp(&self, otp: &str) -> Result<()> {
+        self.otps.write().insert(otp.to_string());
+        Ok(())
+    }
+    async fn take_otp(&self, otp: &str) -> Result<bool> {
+        Ok(self.otps.write().remove(otp))
+    }
+    async fn forward_targets(&self, group: &Group) -> Result<Vec<Group>> {
+        let mut targets: Vec<_> = self
+            .clusters
+            .read()
+            .values()
+            .filter(|cluster| cluster.groups.contains(group))
+            .flat_map(|cluster| cluster.groups.iter())
+            .filter(|target| *target != group)
+            .cloned()
+            .collect();
+        targets.sort_by(|a, b| (a.im.as_str(), &a.id).cmp(&(b.im.as_str(), &b.id)));
+        targets.dedup();
+        Ok(targets)
+    }
+}
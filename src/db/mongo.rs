@@ -0,0 +1,454 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use mongodb::bson::Document;
+use mongodb::bson::{doc, Bson};
+use mongodb::change_stream::event::ChangeStreamEvent;
+use mongodb::options::{
+    ChangeStreamOptions, FullDocumentType, IndexOptions, ReplaceOptions, UpdateModifications,
+};
+use mongodb::{bson, Collection, Database, IndexModel};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::db::{random_cluster_name, Cluster, ClusterChange, ClusterStore, Group};
+
+/// Version of the cluster documents written by this version of the bridge, stored in their
+/// `schema` field. Documents without one are version 0.
+const SCHEMA_VERSION: i32 = 1;
+
+/// Migrations of cluster documents, in order. The one at index `i` upgrades a document from
+/// version `i` to `i + 1`.
+///
+/// Fields added with `#[serde(default)]` need no migration. One is needed when a default is not
+/// the right value for existing documents, or when a field is renamed or changes its type.
+const MIGRATIONS: &[fn(&mut Document)] = &[add_owner_and_admins];
+
+/// Clusters created before owners were recorded have neither an owner nor admins.
+fn add_owner_and_admins(doc: &mut Document) {
+    if !doc.contains_key("owner") {
+        doc.insert("owner", Bson::Null);
+    }
+    if !doc.contains_key("admins") {
+        doc.insert("admins", Bson::Array(vec![]));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MongoStore {
+    database: Database,
+    clusters: Collection<Cluster>,
+    /// Documents keyed by setting name, holding it in `value`.
+    settings: Collection<Document>,
+    /// Documents whose `_id` is an unused one-time password.
+    otps: Collection<Document>,
+}
+
+impl MongoStore {
+    pub async fn connect(uri: &str, db: &str) -> Result<Self> {
+        let client = mongodb::Client::with_uri_str(uri).await?;
+        let db = client.database(db);
+        let clusters = db.collection("clusters");
+        clusters
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {
+                        "name": 1
+                    })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        let store = Self {
+            settings: db.collection("settings"),
+            otps: db.collection("otps"),
+            database: db,
+            clusters,
+        };
+        store.migrate().await?;
+        Ok(store)
+    }
+    /// Bring every cluster document to the current schema version.
+    ///
+    /// Each document is replaced only if it is still at the version it was read at, so instances
+    /// starting at the same time do not overwrite each other's work.
+    async fn migrate(&self) -> Result<()> {
+        let docs = self.clusters.clone_with_type::<Document>();
+        if let Some(newer) = docs
+            .find_one(doc! { "schema": { "$gt": SCHEMA_VERSION } }, None)
+            .await?
+        {
+            bail!(
+                "Cluster {} has schema version {}, but this version of the bridge only supports \
+                up to {}. Please upgrade.",
+                newer.get_str("name").unwrap_or_default(),
+                newer.get_i32("schema").unwrap_or_default(),
+                SCHEMA_VERSION
+            );
+        }
+        let mut outdated = docs
+            .find(
+                doc! {
+                    "$or": [
+                        { "schema": { "$exists": false } },
+                        { "schema": { "$lt": SCHEMA_VERSION } }
+                    ]
+                },
+                None,
+            )
+            .await?;
+        let mut migrated = 0;
+        while let Some(mut doc) = outdated.try_next().await? {
+            let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+            let version = doc.get_i32("schema").ok();
+            for migration in &MIGRATIONS[version.unwrap_or_default() as usize..] {
+                migration(&mut doc);
+            }
+            doc.insert("schema", SCHEMA_VERSION);
+            let schema = version.map_or_else(|| Bson::from(doc! { "$exists": false }), Bson::from);
+            docs.replace_one(doc! { "_id": id, "schema": schema }, doc, None)
+                .await?;
+            migrated += 1;
+        }
+        if migrated > 0 {
+            info!(
+                migrated,
+                version = SCHEMA_VERSION,
+                "cluster documents migrated"
+            );
+        }
+        Ok(())
+    }
+    async fn update_cluster(&self, cluster: &str, update: bson::Document) -> Result<()> {
+        let result = self
+            .clusters
+            .update_one(
+                doc! {
+                    "name": {
+                        "$eq": cluster
+                    }
+                },
+                UpdateModifications::Document(update),
+                None,
+            )
+            .await?;
+        if result.matched_count == 0 {
+            bail!("No such cluster.");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClusterStore for MongoStore {
+    async fn ping(&self) -> Result<()> {
+        self.database.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+    async fn new_cluster(&self, owner: Option<i64>) -> Result<String> {
+        let name = random_cluster_name();
+        let cluster = Cluster {
+            name: name.clone(),
+            groups: Default::default(),
+            owner,
+            admins: Default::default(),
+        };
+        let mut doc = bson::to_document(&cluster)?;
+        doc.insert("schema", SCHEMA_VERSION);
+        self.clusters
+            .clone_with_type::<Document>()
+            .insert_one(doc, None)
+            .await?;
+        Ok(name)
+    }
+    async fn clusters(&self) -> Result<Vec<String>> {
+        Ok(self
+            .clusters
+            .distinct("name", None, None)
+            .await?
+            .into_iter()
+            .map(|doc| {
+                if let Bson::String(s) = doc {
+                    s
+                } else {
+                    unreachable!()
+                }
+            })
+            .collect())
+    }
+    async fn leave(&self, cluster: &str, group: &Group) -> Result<()> {
+        let group = bson::to_document(group)?;
+        let result = self
+            .clusters
+            .update_one(
+                doc! {
+                    "name": {
+                        "$eq": cluster
+                    }
+                },
+                UpdateModifications::Document(doc! {
+                    "$pull": {
+                        "groups": group
+                    }
+                }),
+                None,
+            )
+            .await?;
+        if result.modified_count == 0 {
+            bail!("Group is not in the cluster.");
+        }
+        Ok(())
+    }
+    async fn cluster(&self, name: &str) -> Result<Option<Cluster>> {
+        Ok(self
+            .clusters
+            .find_one(
+                doc! {
+                    "name": {
+                        "$eq": name
+                    }
+                },
+                None,
+            )
+            .await?)
+    }
+    async fn all_clusters(&self) -> Result<Vec<Cluster>> {
+        Ok(self.clusters.find(None, None).await?.try_collect().await?)
+    }
+    async fn rename_cluster(&self, cluster: &str, new_name: &str) -> Result<()> {
+        self.update_cluster(
+            cluster,
+            doc! {
+                "$set": {
+                    "name": new_name
+                }
+            },
+        )
+        .await
+    }
+    async fn join(&self, cluster: &str, group: &Group) -> Result<()> {
+        let group = bson::to_document(group)?;
+        let result = self
+            .clusters
+            .update_one(
+                doc! {
+                    "name": {
+                        "$eq": cluster
+                    }
+                },
+                UpdateModifications::Document(doc! {
+                    "$addToSet": {
+                        "groups": group
+                    }
+                }),
+                None,
+            )
+            .await?;
+        if result.modified_count == 0 {
+            bail!("No cluster modified.");
+        }
+        Ok(())
+    }
+    async fn delete_cluster(&self, cluster: &str) -> Result<()> {
+        let result = self
+            .clusters
+            .delete_one(
+                doc! {
+                    "name": {
+                        "$eq": cluster
+                    }
+                },
+                None,
+            )
+            .await?;
+        if result.deleted_count == 0 {
+            bail!("No cluster deleted.");
+        }
+        Ok(())
+    }
+    async fn put_cluster(&self, cluster: &Cluster) -> Result<()> {
+        let mut doc = bson::to_document(cluster)?;
+        doc.insert("schema", SCHEMA_VERSION);
+        self.clusters
+            .clone_with_type::<Document>()
+            .replace_one(
+                doc! {
+                    "name": {
+                        "$eq": &cluster.name
+                    }
+                },
+                doc,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+    async fn set_owner(&self, cluster: &str, owner: i64) -> Result<()> {
+        self.update_cluster(
+            cluster,
+            doc! {
+                "$set": {
+                    "owner": owner
+                },
+                "$pull": {
+                    "admins": owner
+                }
+            },
+        )
+        .await
+    }
+    async fn add_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.update_cluster(
+            cluster,
+            doc! {
+                "$addToSet": {
+                    "admins": uin
+                }
+            },
+        )
+        .await
+    }
+    async fn remove_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.update_cluster(
+            cluster,
+            doc! {
+                "$pull": {
+                    "admins": uin
+                }
+            },
+        )
+        .await
+    }
+    async fn token(&self) -> Result<Option<String>> {
+        Ok(self
+            .settings
+            .find_one(doc! { "_id": "token" }, None)
+            .await?
+            .and_then(|found| found.get_str("value").ok().map(ToString::to_string)))
+    }
+    async fn set_token(&self, token: &str) -> Result<()> {
+        self.settings
+            .replace_one(
+                doc! { "_id": "token" },
+                doc! { "_id": "token", "value": token },
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+    async fn add_otp(&self, otp: &str) -> Result<()> {
+        self.otps.insert_one(doc! { "_id": otp }, None).await?;
+        Ok(())
+    }
+    async fn take_otp(&self, otp: &str) -> Result<bool> {
+        let result = self.otps.delete_one(doc! { "_id": otp }, None).await?;
+        Ok(result.deleted_count > 0)
+    }
+    async fn forward_targets(&self, group: &Group) -> Result<Vec<Group>> {
+        #[derive(Debug, Deserialize)]
+        struct Targets {
+            targets: Vec<Group>,
+        }
+        let group = bson::to_document(group)?;
+        let mut cursor = self
+            .clusters
+            .aggregate(
+                [
+                    doc! {
+                        "$match": doc! {
+                            "groups": doc! {
+                                "$all": [
+                                    &group
+                                ]
+                            }
+                        }
+                    },
+                    doc! {
+                        "$unwind": doc! {
+                            "path": "$groups"
+                        }
+                    },
+                    doc! {
+                        "$match": doc! {
+                            "groups": doc! {
+                                "$ne": group
+                            }
+                        }
+                    },
+                    doc! {
+                        "$group": doc! {
+                            "_id": Bson::Null,
+                            "targets": doc! {
+                                "$addToSet": "$groups"
+                            }
+                        }
+                    },
+                ],
+                None,
+            )
+            .await?
+            .with_type::<Targets>();
+        Ok(if cursor.advance().await? {
+            let targets = cursor.deserialize_current()?;
+            targets.targets
+        } else {
+            vec![]
+        })
+    }
+    /// Requires MongoDB to run as a replica set.
+    async fn watch(&self) -> Result<Option<BoxStream<'static, Result<ClusterChange>>>> {
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .build();
+        let changes = self
+            .clusters
+            .clone_with_type::<bson::Document>()
+            .watch(None, options)
+            .await?;
+        Ok(Some(changes.map_ok(cluster_change).err_into().boxed()))
+    }
+}
+
+/// The cluster a change stream event is about, if it tells. Deletions only carry the `_id`, and
+/// renames only the new name.
+fn cluster_change(event: ChangeStreamEvent<Document>) -> ClusterChange {
+    let renamed = event
+        .update_description
+        .as_ref()
+        .map_or(false, |update| update.updated_fields.contains_key("name"));
+    let name = event
+        .full_document
+        .as_ref()
+        .and_then(|doc| doc.get_str("name").ok());
+    match name {
+        Some(name) if !renamed => ClusterChange::Cluster(name.to_string()),
+        _ => ClusterChange::Any,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, Bson};
+
+    use super::{add_owner_and_admins, MIGRATIONS, SCHEMA_VERSION};
+
+    #[test]
+    fn every_version_has_a_migration() {
+        assert_eq!(MIGRATIONS.len(), SCHEMA_VERSION as usize);
+    }
+
+    #[test]
+    fn missing_owner_and_admins_are_added() {
+        let mut old = doc! { "name": "a", "groups": [] };
+        add_owner_and_admins(&mut old);
+        assert_eq!(old.get("owner"), Some(&Bson::Null));
+        assert_eq!(old.get_array("admins").unwrap(), &Vec::<Bson>::new());
+
+        let mut owned = doc! { "name": "b", "groups": [], "owner": 1_i64, "admins": [2_i64] };
+        add_owner_and_admins(&mut owned);
+        assert_eq!(owned.get_i64("owner").unwrap(), 1);
+        assert_eq!(owned.get_array("admins").unwrap().len(), 1);
+    }
+}
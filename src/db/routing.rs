@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use parking_lot::RwLock;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::db::{Cluster, ClusterChange, ClusterStore, Group, DB};
+
+/// Delay before watching for changes again after the change stream broke or could not be opened.
+const REWATCH_DELAY: Duration = Duration::from_secs(10);
+/// How often the table is reloaded from stores without a change stream.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Forward targets of every group, so that forwarding never waits on the storage.
+#[derive(Debug, Default)]
+struct Table {
+    clusters: HashMap<String, HashSet<Group>>,
+    /// Clusters each group is in.
+    memberships: HashMap<Group, HashSet<String>>,
+    targets: HashMap<Group, Vec<Group>>,
+}
+
+impl Table {
+    fn build(clusters: Vec<Cluster>) -> Self {
+        let mut table = Self::default();
+        for cluster in clusters {
+            table.replace(&cluster.name, Some(cluster.groups));
+        }
+        table
+    }
+    /// Replace the groups of a cluster, or remove it if `None`, and update the targets of every
+    /// affected group.
+    fn replace(&mut self, cluster: &str, groups: Option<HashSet<Group>>) {
+        let old = self.clusters.remove(cluster).unwrap_or_default();
+        for group in &old {
+            if let Some(clusters) = self.memberships.get_mut(group) {
+                clusters.remove(cluster);
+            }
+        }
+        let exists = groups.is_some();
+        let new = groups.unwrap_or_default();
+        for group in &new {
+            self.memberships
+                .entry(group.clone())
+                .or_default()
+                .insert(cluster.to_string());
+        }
+        let affected: Vec<_> = old.union(&new).cloned().collect();
+        if exists {
+            self.clusters.insert(cluster.to_string(), new);
+        }
+        for group in &affected {
+            self.retarget(group);
+        }
+    }
+    fn retarget(&mut self, group: &Group) {
+        let clusters = self.memberships.get(group).cloned().unwrap_or_default();
+        if clusters.is_empty() {
+            self.memberships.remove(group);
+            self.targets.remove(group);
+            return;
+        }
+        let targets: HashSet<_> = clusters
+            .iter()
+            .filter_map(|cluster| self.clusters.get(cluster))
+            .flatten()
+            .filter(|target| *target != group)
+            .cloned()
+            .collect();
+        self.targets
+            .insert(group.clone(), targets.into_iter().collect());
+    }
+}
+
+/// Wraps a store with an in-process routing table for `forward_targets`.
+///
+/// The table is loaded at startup and updated after every write through this store. Once
+/// [`watch`](Self::watch) is called, changes made by other instances or the offline commands arrive
+/// through the store's change stream and update the changed cluster. Stores without one are
+/// reloaded every [`RELOAD_INTERVAL`] instead.
+pub struct RoutingCache {
+    store: DB,
+    routing: Arc<Routing>,
+}
+
+#[derive(Debug)]
+struct Routing {
+    table: RwLock<Table>,
+    /// Held from reading the store until the result is applied, so that an update never replaces
+    /// one that read the store after it.
+    updating: Mutex<()>,
+}
+
+impl Routing {
+    /// Reload a cluster from the store.
+    async fn refresh(&self, store: &DB, cluster: &str) -> Result<()> {
+        let _updating = self.updating.lock().await;
+        let groups = store.cluster(cluster).await?.map(|found| found.groups);
+        self.table.write().replace(cluster, groups);
+        Ok(())
+    }
+    /// Reload every cluster from the store.
+    async fn reload(&self, store: &DB) {
+        let _updating = self.updating.lock().await;
+        match store.all_clusters().await {
+            Ok(clusters) => *self.table.write() = Table::build(clusters),
+            Err(e) => warn!(?e, "failed to reload routing table"),
+        }
+    }
+}
+
+impl RoutingCache {
+    pub async fn new(store: DB) -> Result<Self> {
+        let routing = Routing {
+            table: RwLock::new(Table::build(store.all_clusters().await?)),
+            updating: Mutex::default(),
+        };
+        Ok(Self {
+            store,
+            routing: Arc::new(routing),
+        })
+    }
+    /// Follow changes made by others in the background, for as long as the process runs.
+    pub fn watch(&self) {
+        tokio::spawn(watch(self.store.clone(), self.routing.clone()));
+    }
+    /// Reload a cluster from the store after writing to it.
+    async fn refresh(&self, cluster: &str) -> Result<()> {
+        self.routing.refresh(&self.store, cluster).await
+    }
+}
+
+/// Keep the table in sync with changes made by other instances.
+async fn watch(store: DB, routing: Arc<Routing>) {
+    let mut failures = 0;
+    loop {
+        let mut changes = match store.watch().await {
+            Ok(Some(changes)) => changes,
+            Ok(None) => break,
+            Err(e) => {
+                // E.g. a standalone MongoDB server, which will keep failing. Say so only once.
+                if failures == 0 {
+                    warn!(
+                        ?e,
+                        "failed to watch for cluster changes, retrying and reloading clusters \
+                        every {:?} meanwhile",
+                        REWATCH_DELAY
+                    );
+                } else {
+                    debug!(?e, "failed to watch for cluster changes");
+                }
+                failures += 1;
+                tokio::time::sleep(REWATCH_DELAY).await;
+                routing.reload(&store).await;
+                continue;
+            }
+        };
+        failures = 0;
+        info!("watching for cluster changes");
+        while let Some(change) = changes.next().await {
+            match change {
+                Ok(ClusterChange::Cluster(cluster)) => {
+                    debug!(cluster, "cluster changed, updating routing table");
+                    if let Err(e) = routing.refresh(&store, &cluster).await {
+                        warn!(?e, cluster, "failed to update routing table");
+                    }
+                }
+                Ok(ClusterChange::Any) => {
+                    debug!("clusters changed, reloading routing table");
+                    routing.reload(&store).await;
+                }
+                Err(e) => {
+                    warn!(?e, "cluster change stream broke");
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(REWATCH_DELAY).await;
+        // Changes may have been missed while not watching.
+        routing.reload(&store).await;
+    }
+    info!(
+        interval = ?RELOAD_INTERVAL,
+        "storage has no change stream, reloading clusters periodically"
+    );
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        routing.reload(&store).await;
+    }
+}
+
+#[async_trait]
+impl ClusterStore for RoutingCache {
+    async fn ping(&self) -> Result<()> {
+        self.store.ping().await
+    }
+    async fn new_cluster(&self, owner: Option<i64>) -> Result<String> {
+        let name = self.store.new_cluster(owner).await?;
+        self.refresh(&name).await?;
+        Ok(name)
+    }
+    async fn clusters(&self) -> Result<Vec<String>> {
+        self.store.clusters().await
+    }
+    async fn all_clusters(&self) -> Result<Vec<Cluster>> {
+        self.store.all_clusters().await
+    }
+    async fn cluster(&self, name: &str) -> Result<Option<Cluster>> {
+        self.store.cluster(name).await
+    }
+    async fn rename_cluster(&self, cluster: &str, new_name: &str) -> Result<()> {
+        self.store.rename_cluster(cluster, new_name).await?;
+        self.refresh(cluster).await?;
+        self.refresh(new_name).await
+    }
+    async fn delete_cluster(&self, cluster: &str) -> Result<()> {
+        self.store.delete_cluster(cluster).await?;
+        self.refresh(cluster).await
+    }
+    async fn put_cluster(&self, cluster: &Cluster) -> Result<()> {
+        self.store.put_cluster(cluster).await?;
+        self.refresh(&cluster.name).await
+    }
+    async fn join(&self, cluster: &str, group: &Group) -> Result<()> {
+        self.store.join(cluster, group).await?;
+        self.refresh(cluster).await
+    }
+    async fn leave(&self, cluster: &str, group: &Group) -> Result<()> {
+        self.store.leave(cluster, group).await?;
+        self.refresh(cluster).await
+    }
+    async fn set_owner(&self, cluster: &str, owner: i64) -> Result<()> {
+        self.store.set_owner(cluster, owner).await
+    }
+    async fn add_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.store.add_admin(cluster, uin).await
+    }
+    async fn remove_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.store.remove_admin(cluster, uin).await
+    }
+    async fn token(&self) -> Result<Option<String>> {
+        self.store.token().await
+    }
+    async fn set_token(&self, token: &str) -> Result<()> {
+        self.store.set_token(token).await
+    }
+    async fn add_otp(&self, otp: &str) -> Result<()> {
+        self.store.add_otp(otp).await
+    }
+    async fn take_otp(&self, otp: &str) -> Result<bool> {
+        self.store.take_otp(otp).await
+    }
+    async fn forward_targets(&self, group: &Group) -> Result<Vec<Group>> {
+        Ok(self
+            .routing
+            .table
+            .read()
+            .targets
+            .get(group)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::Table;
+    use crate::db::Group;
+
+    fn groups(ids: &[i64]) -> HashSet<Group> {
+        ids.iter().copied().map(Group::from_qq).collect()
+    }
+
+    fn targets(table: &Table, id: i64) -> HashSet<Group> {
+        table
+            .targets
+            .get(&Group::from_qq(id))
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn targets_span_all_clusters_of_group() {
+        let mut table = Table::default();
+        table.replace("a", Some(groups(&[1, 2])));
+        table.replace("b", Some(groups(&[1, 3])));
+        assert_eq!(targets(&table, 1), groups(&[2, 3]));
+        assert_eq!(targets(&table, 2), groups(&[1]));
+        assert_eq!(targets(&table, 3), groups(&[1]));
+    }
+
+    #[test]
+    fn leaving_and_deleting_update_targets() {
+        let mut table = Table::default();
+        table.replace("a", Some(groups(&[1, 2, 3])));
+        table.replace("a", Some(groups(&[1, 2])));
+        assert_eq!(targets(&table, 1), groups(&[2]));
+        assert!(targets(&table, 3).is_empty());
+
+        table.replace("a", None);
+        assert!(table.targets.is_empty());
+        assert!(table.clusters.is_empty());
+    }
+}
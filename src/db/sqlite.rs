@@ -0,0 +1,390 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use tracing::info;
+
+use crate::db::{random_cluster_name, Cluster, ClusterStore, Group};
+
+/// Schema migrations, in order. The one at index `i` upgrades a database from `user_version` `i`
+/// to `i + 1`. Never edit one that has been released; add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // Tables may already exist in databases created before the schema was versioned.
+    "
+CREATE TABLE IF NOT EXISTS clusters (
+    name TEXT PRIMARY KEY,
+    owner INTEGER
+);
+CREATE TABLE IF NOT EXISTS cluster_groups (
+    cluster TEXT NOT NULL REFERENCES clusters (name) ON UPDATE CASCADE ON DELETE CASCADE,
+    im TEXT NOT NULL,
+    id TEXT NOT NULL,
+    PRIMARY KEY (cluster, im, id)
+);
+CREATE INDEX IF NOT EXISTS cluster_groups_group ON cluster_groups (im, id);
+CREATE TABLE IF NOT EXISTS cluster_admins (
+    cluster TEXT NOT NULL REFERENCES clusters (name) ON UPDATE CASCADE ON DELETE CASCADE,
+    uin INTEGER NOT NULL,
+    PRIMARY KEY (cluster, uin)
+);
+",
+    "
+CREATE TABLE settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE otps (
+    otp TEXT PRIMARY KEY
+);
+",
+];
+
+/// Embedded storage for deployments without a MongoDB server.
+///
+/// The connection is blocking, so every query runs on the blocking thread pool.
+#[derive(Debug, Clone)]
+pub struct SqliteStore(Arc<Mutex<Connection>>);
+
+impl SqliteStore {
+    pub async fn open(path: &Path) -> Result<Self> {
+        let path = path.to_path_buf();
+        let conn = tokio::task::spawn_blocking(move || -> Result<_> {
+            let mut conn = Connection::open(path)?;
+            conn.pragma_update(None, "foreign_keys", true)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            migrate(&mut conn)?;
+            Ok(conn)
+        })
+        .await??;
+        Ok(Self(Arc::new(Mutex::new(conn))))
+    }
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.0.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock())).await?
+    }
+    /// Run `f` in a transaction on an existing cluster.
+    async fn with_cluster<T, F>(&self, cluster: &str, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction, &str) -> Result<T> + Send + 'static,
+    {
+        let cluster = cluster.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, &cluster)? {
+                bail!("No such cluster.");
+            }
+            let result = f(&tx, &cluster)?;
+            tx.commit()?;
+            Ok(result)
+        })
+        .await
+    }
+}
+
+/// Bring the schema to the latest version, one transaction per migration.
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let mut version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let latest = MIGRATIONS.len() as i64;
+    if version > latest {
+        bail!(
+            "Database has schema version {}, but this version of the bridge only supports up to \
+            {}. Please upgrade.",
+            version,
+            latest
+        );
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        version += 1;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        info!(version, "database schema migrated");
+    }
+    Ok(())
+}
+
+fn exists(conn: &Connection, cluster: &str) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM clusters WHERE name = ?1",
+            params![cluster],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+fn load_cluster(conn: &Connection, name: &str) -> Result<Option<Cluster>> {
+    let Some(owner) = conn
+        .query_row(
+            "SELECT owner FROM clusters WHERE name = ?1",
+            params![name],
+            |row| row.get::<_, Option<i64>>(0),
+        )
+        .optional()? else {
+        return Ok(None);
+    };
+    let groups = conn
+        .prepare_cached("SELECT im, id FROM cluster_groups WHERE cluster = ?1")?
+        .query_map(params![name], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .map(|row| {
+            let (im, id) = row?;
+            Ok(Group {
+                im: im.parse()?,
+                id,
+            })
+        })
+        .collect::<Result<_>>()?;
+    let admins = conn
+        .prepare_cached("SELECT uin FROM cluster_admins WHERE cluster = ?1")?
+        .query_map(params![name], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Cluster {
+        name: name.to_string(),
+        groups,
+        owner,
+        admins,
+    }))
+}
+
+#[async_trait]
+impl ClusterStore for SqliteStore {
+    async fn ping(&self) -> Result<()> {
+        self.with_conn(|conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+    }
+    async fn new_cluster(&self, owner: Option<i64>) -> Result<String> {
+        let name = random_cluster_name();
+        let inserted = name.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO clusters (name, owner) VALUES (?1, ?2)",
+                params![inserted, owner],
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(name)
+    }
+    async fn clusters(&self) -> Result<Vec<String>> {
+        self.with_conn(|conn| {
+            Ok(conn
+                .prepare_cached("SELECT name FROM clusters")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+    async fn all_clusters(&self) -> Result<Vec<Cluster>> {
+        self.with_conn(|conn| {
+            let names: Vec<String> = conn
+                .prepare_cached("SELECT name FROM clusters")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            names
+                .iter()
+                .filter_map(|name| load_cluster(conn, name).transpose())
+                .collect()
+        })
+        .await
+    }
+    async fn cluster(&self, name: &str) -> Result<Option<Cluster>> {
+        let name = name.to_string();
+        self.with_conn(move |conn| load_cluster(conn, &name)).await
+    }
+    async fn rename_cluster(&self, cluster: &str, new_name: &str) -> Result<()> {
+        let new_name = new_name.to_string();
+        self.with_cluster(cluster, move |tx, cluster| {
+            tx.execute(
+                "UPDATE clusters SET name = ?2 WHERE name = ?1",
+                params![cluster, new_name],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    async fn delete_cluster(&self, cluster: &str) -> Result<()> {
+        let cluster = cluster.to_string();
+        self.with_conn(move |conn| {
+            if conn.execute("DELETE FROM clusters WHERE name = ?1", params![cluster])? == 0 {
+                bail!("No cluster deleted.");
+            }
+            Ok(())
+        })
+        .await
+    }
+    async fn put_cluster(&self, cluster: &Cluster) -> Result<()> {
+        let cluster = cluster.clone();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO clusters (name, owner) VALUES (?1, ?2)
+                ON CONFLICT (name) DO UPDATE SET owner = excluded.owner",
+                params![cluster.name, cluster.owner],
+            )?;
+            tx.execute(
+                "DELETE FROM cluster_groups WHERE cluster = ?1",
+                params![cluster.name],
+            )?;
+            tx.execute(
+                "DELETE FROM cluster_admins WHERE cluster = ?1",
+                params![cluster.name],
+            )?;
+            for group in &cluster.groups {
+                tx.execute(
+                    "INSERT INTO cluster_groups (cluster, im, id) VALUES (?1, ?2, ?3)",
+                    params![cluster.name, group.im.as_str(), group.id],
+                )?;
+            }
+            for uin in &cluster.admins {
+                tx.execute(
+                    "INSERT INTO cluster_admins (cluster, uin) VALUES (?1, ?2)",
+                    params![cluster.name, uin],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+    async fn join(&self, cluster: &str, group: &Group) -> Result<()> {
+        let group = group.clone();
+        self.with_cluster(cluster, move |tx, cluster| {
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO cluster_groups (cluster, im, id) VALUES (?1, ?2, ?3)",
+                params![cluster, group.im.as_str(), group.id],
+            )?;
+            if inserted == 0 {
+                bail!("No cluster modified.");
+            }
+            Ok(())
+        })
+        .await
+    }
+    async fn leave(&self, cluster: &str, group: &Group) -> Result<()> {
+        let group = group.clone();
+        self.with_cluster(cluster, move |tx, cluster| {
+            let deleted = tx.execute(
+                "DELETE FROM cluster_groups WHERE cluster = ?1 AND im = ?2 AND id = ?3",
+                params![cluster, group.im.as_str(), group.id],
+            )?;
+            if deleted == 0 {
+                bail!("Group is not in the cluster.");
+            }
+            Ok(())
+        })
+        .await
+    }
+    async fn set_owner(&self, cluster: &str, owner: i64) -> Result<()> {
+        self.with_cluster(cluster, move |tx, cluster| {
+            tx.execute(
+                "UPDATE clusters SET owner = ?2 WHERE name = ?1",
+                params![cluster, owner],
+            )?;
+            tx.execute(
+                "DELETE FROM cluster_admins WHERE cluster = ?1 AND uin = ?2",
+                params![cluster, owner],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    async fn add_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.with_cluster(cluster, move |tx, cluster| {
+            tx.execute(
+                "INSERT OR IGNORE INTO cluster_admins (cluster, uin) VALUES (?1, ?2)",
+                params![cluster, uin],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    async fn remove_admin(&self, cluster: &str, uin: i64) -> Result<()> {
+        self.with_cluster(cluster, move |tx, cluster| {
+            tx.execute(
+                "DELETE FROM cluster_admins WHERE cluster = ?1 AND uin = ?2",
+                params![cluster, uin],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    async fn token(&self) -> Result<Option<String>> {
+        self.with_conn(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT value FROM settings WHERE key = 'token'",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+    async fn set_token(&self, token: &str) -> Result<()> {
+        let token = token.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO settings (key, value) VALUES ('token', ?1)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                params![token],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    async fn add_otp(&self, otp: &str) -> Result<()> {
+        let otp = otp.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("INSERT INTO otps (otp) VALUES (?1)", params![otp])?;
+            Ok(())
+        })
+        .await
+    }
+    async fn take_otp(&self, otp: &str) -> Result<bool> {
+        let otp = otp.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn.execute("DELETE FROM otps WHERE otp = ?1", params![otp])? > 0)
+        })
+        .await
+    }
+    async fn forward_targets(&self, group: &Group) -> Result<Vec<Group>> {
+        let group = group.clone();
+        self.with_conn(move |conn| {
+            conn.prepare_cached(
+                "SELECT DISTINCT target.im, target.id
+                FROM cluster_groups source
+                JOIN cluster_groups target ON target.cluster = source.cluster
+                WHERE source.im = ?1 AND source.id = ?2
+                AND NOT (target.im = ?1 AND target.id = ?2)",
+            )?
+            .query_map(params![group.im.as_str(), group.id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .map(|row| {
+                let (im, id) = row?;
+                Ok(Group {
+                    im: im.parse()?,
+                    id,
+                })
+            })
+            .collect()
+        })
+        .await
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::OnceCell;
+
+use crate::bridge::{Backend, BridgedMessage};
+use crate::config::DiscordConfig;
+
+pub use crate::discord::gateway::run;
+
+mod gateway;
+
+/// Name of the webhooks the bridge creates, and looks for before creating one.
+const WEBHOOK_NAME: &str = "im-bridge";
+/// Longest message content Discord accepts.
+const MAX_CONTENT: usize = 2000;
+/// Longest username a webhook message may use.
+const MAX_USERNAME: usize = 80;
+/// Username for senders without a name, which Discord would reject.
+const UNNAMED: &str = "(unnamed)";
+
+#[derive(Debug, Clone, Deserialize)]
+struct Webhook {
+    id: String,
+    /// Only present for webhooks the bot can use.
+    token: Option<String>,
+    name: Option<String>,
+}
+
+/// REST client of a Discord bot.
+///
+/// Bridged messages are posted through a webhook per channel, so that each sender shows up with
+/// their own name and avatar instead of the bot's.
+pub struct Discord {
+    http: reqwest::Client,
+    api: String,
+    token: String,
+    /// Webhook of each channel, once looked up or created. Concurrent first sends to a channel
+    /// wait for the same lookup, so that only one webhook is created.
+    webhooks: Mutex<HashMap<String, Arc<OnceCell<(String, String)>>>>,
+}
+
+impl Discord {
+    pub fn new(config: &DiscordConfig) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api: config.api.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+            webhooks: Mutex::default(),
+        }
+    }
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}{}", self.api, path))
+            .header("Authorization", format!("Bot {}", self.token))
+    }
+    /// Id and token of the channel's webhook.
+    async fn webhook(&self, channel: &str) -> Result<(String, String)> {
+        let cell = self
+            .webhooks
+            .lock()
+            .entry(channel.to_string())
+            .or_default()
+            .clone();
+        cell.get_or_try_init(|| self.find_webhook(channel))
+            .await
+            .cloned()
+    }
+    /// Look up the channel's webhook, creating it if there is none.
+    async fn find_webhook(&self, channel: &str) -> Result<(String, String)> {
+        let path = format!("/channels/{}/webhooks", channel);
+        let existing: Vec<Webhook> = self
+            .request(Method::GET, &path)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let webhook = match existing
+            .into_iter()
+            .find(|hook| hook.name.as_deref() == Some(WEBHOOK_NAME) && hook.token.is_some())
+        {
+            Some(hook) => hook,
+            None => {
+                self.request(Method::POST, &path)
+                    .json(&json!({ "name": WEBHOOK_NAME }))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?
+            }
+        };
+        let token = webhook
+            .token
+            .ok_or_else(|| anyhow!("webhook {} of channel {} has no token", webhook.id, channel))?;
+        Ok((webhook.id, token))
+    }
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+#[async_trait]
+impl Backend for Discord {
+    async fn send(&self, channel: &str, message: &BridgedMessage) -> Result<()> {
+        // Discord rejects messages without content.
+        if message.text.trim().is_empty() {
+            return Ok(());
+        }
+        let username = match truncate(message.sender.trim(), MAX_USERNAME) {
+            name if name.is_empty() => UNNAMED.to_string(),
+            name => name,
+        };
+        let (id, token) = self.webhook(channel).await?;
+        let response = self
+            .http
+            .post(format!("{}/webhooks/{}/{}", self.api, id, token))
+            .json(&json!({
+                "content": truncate(&message.text, MAX_CONTENT),
+                "username": username,
+                "avatar_url": message.avatar,
+                // Bridged text must not ping anyone on Discord.
+                "allowed_mentions": { "parse": [] },
+            }))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            // Deleted by someone on Discord. Create a new one next time.
+            self.webhooks.lock().remove(channel);
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+    async fn send_notice(&self, channel: &str, text: &str) -> Result<()> {
+        self.request(Method::POST, &format!("/channels/{}/messages", channel))
+            .json(&json!({
+                "content": truncate(text, MAX_CONTENT),
+                "allowed_mentions": { "parse": [] },
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use axum::extract::{Path, State};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use parking_lot::Mutex;
+    use serde_json::{json, Value};
+
+    use super::Discord;
+    use crate::bridge::{Backend, BridgedMessage};
+    use crate::config::DiscordConfig;
+    use crate::db::Group;
+
+    /// What the mock API received.
+    #[derive(Debug, Default)]
+    struct Received {
+        created: Vec<String>,
+        executed: Vec<Value>,
+    }
+
+    type Shared = Arc<Mutex<Received>>;
+
+    async fn list(Path(_): Path<String>) -> Json<Value> {
+        Json(json!([{ "id": "1", "name": "someone else's", "token": "x" }]))
+    }
+
+    async fn create(State(received): State<Shared>, Path(channel): Path<String>) -> Json<Value> {
+        received.lock().created.push(channel);
+        Json(json!({ "id": "2", "name": "im-bridge", "token": "secret" }))
+    }
+
+    async fn execute(
+        State(received): State<Shared>,
+        Path((id, token)): Path<(String, String)>,
+        Json(body): Json<Value>,
+    ) {
+        assert_eq!((id.as_str(), token.as_str()), ("2", "secret"));
+        received.lock().executed.push(body);
+    }
+
+    #[tokio::test]
+    async fn sends_through_one_webhook_per_channel() {
+        let received = Shared::default();
+        let app = Router::new()
+            .route("/channels/:channel/webhooks", get(list).post(create))
+            .route("/webhooks/:id/:token", post(execute))
+            .with_state(received.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let discord = Discord::new(&DiscordConfig {
+            token: "bot".to_string(),
+            api: format!("http://{}", addr),
+            gateway: String::new(),
+        });
+        let message = BridgedMessage {
+            source: Group::from_qq(100),
+            sender_id: "1".to_string(),
+            sender: "Alice".to_string(),
+            avatar: Some("https://example.com/alice.png".to_string()),
+            text: "hello".to_string(),
+        };
+        // Sent concurrently, so that both look for a webhook before either created one.
+        let (first, second) =
+            tokio::join!(discord.send("42", &message), discord.send("42", &message));
+        first.unwrap();
+        second.unwrap();
+        let unnamed = BridgedMessage {
+            sender: " ".to_string(),
+            ..message.clone()
+        };
+        discord.send("42", &unnamed).await.unwrap();
+        let blank = BridgedMessage {
+            text: String::new(),
+            ..message
+        };
+        discord.send("42", &blank).await.unwrap();
+
+        let received = received.lock();
+        assert_eq!(received.created, ["42"]);
+        assert_eq!(received.executed.len(), 3);
+        assert_eq!(received.executed[2]["username"], "(unnamed)");
+        assert_eq!(received.executed[0]["username"], "Alice");
+        assert_eq!(received.executed[0]["content"], "hello");
+        assert_eq!(
+            received.executed[0]["avatar_url"],
+            "https://example.com/alice.png"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info, warn};
+
+use crate::bridge::{BridgedMessage, Relay};
+use crate::config::DiscordConfig;
+use crate::db::{Group, IM};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// A session that lasted this long resets the backoff.
+const STABLE_SESSION: Duration = Duration::from_secs(10 * 60);
+
+const DISPATCH: u8 = 0;
+const HEARTBEAT: u8 = 1;
+const IDENTIFY: u8 = 2;
+const RECONNECT: u8 = 7;
+const INVALID_SESSION: u8 = 9;
+const HELLO: u8 = 10;
+const HEARTBEAT_ACK: u8 = 11;
+
+/// Guild messages and their content.
+const INTENTS: u64 = (1 << 9) | (1 << 15);
+
+/// Close codes telling that the bot is misconfigured, e.g. a wrong token or intents it is not
+/// allowed. Reconnecting would fail the same way.
+const FATAL_CLOSE_CODES: &[u16] = &[4004, 4010, 4011, 4012, 4013, 4014];
+
+/// The gateway refused the session for good.
+#[derive(Debug)]
+struct Fatal {
+    code: u16,
+    reason: String,
+}
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Discord gateway closed the connection with {}: {}",
+            self.code, self.reason
+        )
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+    op: u8,
+    #[serde(default)]
+    d: Value,
+    s: Option<u64>,
+    t: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Author {
+    id: String,
+    username: String,
+    global_name: Option<String>,
+    #[serde(default)]
+    bot: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Member {
+    nick: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Attachment {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageCreate {
+    channel_id: String,
+    content: String,
+    author: Author,
+    member: Option<Member>,
+    webhook_id: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+}
+
+impl MessageCreate {
+    /// `None` for messages that must not be bridged, including those the bridge posted itself.
+    fn bridged(self) -> Option<BridgedMessage> {
+        if self.webhook_id.is_some() || self.author.bot {
+            return None;
+        }
+        let text = std::iter::once(self.content)
+            .chain(
+                self.attachments
+                    .into_iter()
+                    .map(|attachment| attachment.url),
+            )
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if text.is_empty() {
+            return None;
+        }
+        let sender = self
+            .member
+            .and_then(|member| member.nick)
+            .or(self.author.global_name)
+            .unwrap_or(self.author.username);
+        Some(BridgedMessage {
+            source: Group {
+                im: IM::Discord,
+                id: self.channel_id,
+            },
+            sender_id: self.author.id,
+            sender,
+            avatar: None,
+            text,
+        })
+    }
+}
+
+/// Keep a gateway session open, relaying messages of bridged channels, and reconnect with
+/// exponential backoff whenever it ends, unless the gateway refused the bot's configuration.
+pub async fn run(config: DiscordConfig, relay: Relay) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let started = Instant::now();
+        match session(&config, &relay).await {
+            Ok(()) => warn!("Discord gateway session ended"),
+            Err(e) if e.is::<Fatal>() => {
+                error!(%e, "not reconnecting, Discord messages are not bridged until restarted");
+                return;
+            }
+            Err(e) => error!(?e, "Discord gateway session failed"),
+        }
+        if started.elapsed() >= STABLE_SESSION {
+            backoff = INITIAL_BACKOFF;
+        }
+        info!(?backoff, "reconnecting to Discord gateway");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn heartbeat(seq: Option<u64>) -> Message {
+    Message::Text(json!({ "op": HEARTBEAT, "d": seq }).to_string())
+}
+
+async fn session(config: &DiscordConfig, relay: &Relay) -> Result<()> {
+    let (socket, _) = tokio_tungstenite::connect_async(&config.gateway).await?;
+    let (mut sink, mut stream) = socket.split();
+
+    let Some(hello) = stream.next().await else {
+        bail!("gateway closed before saying hello");
+    };
+    let hello: Payload = serde_json::from_str(hello?.to_text()?)?;
+    if hello.op != HELLO {
+        bail!("expected hello, got op {}", hello.op);
+    }
+    let interval = hello.d["heartbeat_interval"]
+        .as_u64()
+        .context("hello without heartbeat interval")?;
+    sink.send(Message::Text(
+        json!({
+            "op": IDENTIFY,
+            "d": {
+                "token": config.token,
+                "intents": INTENTS,
+                "properties": {
+                    "os": std::env::consts::OS,
+                    "browser": "im-bridge",
+                    "device": "im-bridge",
+                },
+            },
+        })
+        .to_string(),
+    ))
+    .await?;
+    info!("connected to Discord gateway");
+
+    let mut heartbeat = tokio::time::interval(Duration::from_millis(interval));
+    let mut seq = None;
+    // A connection that stopped acknowledging heartbeats is dead without being closed.
+    let mut acked = true;
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                if !acked {
+                    bail!("no heartbeat ACK since the last heartbeat");
+                }
+                sink.send(heartbeat(seq)).await?;
+                acked = false;
+            }
+            message = stream.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(frame))) => {
+                        debug!(?frame, "Discord gateway closed the connection");
+                        match frame {
+                            Some(frame) if FATAL_CLOSE_CODES.contains(&u16::from(frame.code)) => {
+                                return Err(Fatal {
+                                    code: frame.code.into(),
+                                    reason: frame.reason.into_owned(),
+                                }
+                                .into());
+                            }
+                            _ => return Ok(()),
+                        }
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                };
+                let payload: Payload = serde_json::from_str(&text)?;
+                if payload.s.is_some() {
+                    seq = payload.s;
+                }
+                match payload.op {
+                    DISPATCH if payload.t.as_deref() == Some("MESSAGE_CREATE") => {
+                        match serde_json::from_value::<MessageCreate>(payload.d) {
+                            Ok(created) => {
+                                if let Some(message) = created.bridged() {
+                                    relay.relay(message).await;
+                                }
+                            }
+                            Err(e) => warn!(?e, "unexpected Discord message"),
+                        }
+                    }
+                    HEARTBEAT => {
+                        sink.send(heartbeat(seq)).await?;
+                    }
+                    HEARTBEAT_ACK => acked = true,
+                    RECONNECT | INVALID_SESSION => return Ok(()),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use futures::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{session, Fatal, HEARTBEAT, HELLO, IDENTIFY};
+    use crate::accounts::Accounts;
+    use crate::bridge::{Backends, Relay};
+    use crate::config::DiscordConfig;
+    use crate::db::MemoryStore;
+    use crate::status::ForwardStats;
+
+    #[tokio::test]
+    async fn reconnects_without_heartbeat_ack() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // A gateway that says hello and then never acknowledges a heartbeat.
+        let gateway = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            let hello = json!({ "op": HELLO, "d": { "heartbeat_interval": 50 } });
+            socket.send(Message::Text(hello.to_string())).await.unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(Message::Text(text))) = socket.next().await {
+                received.push(serde_json::from_str::<Value>(&text).unwrap());
+            }
+            received
+        });
+
+        let config = DiscordConfig {
+            token: "bot".to_string(),
+            api: String::new(),
+            gateway: format!("ws://{}", addr),
+        };
+        let relay = Relay {
+            db: Arc::new(MemoryStore::default()),
+            accounts: Accounts::default(),
+            backends: Backends::new(HashMap::new()),
+            stats: ForwardStats::default(),
+        };
+        let e = session(&config, &relay).await.unwrap_err();
+        assert!(e.to_string().contains("heartbeat ACK"), "{:?}", e);
+
+        let received = gateway.await.unwrap();
+        assert_eq!(received[0]["op"], IDENTIFY);
+        assert_eq!(received[0]["d"]["token"], "bot");
+        assert_eq!(received[1]["op"], HEARTBEAT);
+        assert_eq!(received.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn authentication_failure_is_fatal() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // A gateway that rejects the token right after identifying.
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            let hello = json!({ "op": HELLO, "d": { "heartbeat_interval": 60000 } });
+            socket.send(Message::Text(hello.to_string())).await.unwrap();
+            socket.next().await;
+            let frame = CloseFrame {
+                code: CloseCode::from(4004),
+                reason: "Authentication failed.".into(),
+            };
+            socket.close(Some(frame)).await.unwrap();
+            while socket.next().await.is_some() {}
+        });
+
+        let config = DiscordConfig {
+            token: "wrong".to_string(),
+            api: String::new(),
+            gateway: format!("ws://{}", addr),
+        };
+        let relay = Relay {
+            db: Arc::new(MemoryStore::default()),
+            accounts: Accounts::default(),
+            backends: Backends::new(HashMap::new()),
+            stats: ForwardStats::default(),
+        };
+        let e = session(&config, &relay).await.unwrap_err();
+        let fatal = e.downcast_ref::<Fatal>().expect("fatal error");
+        assert_eq!(fatal.code, 4004);
+    }
+}
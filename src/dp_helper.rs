@@ -5,13 +5,26 @@ use async_trait::async_trait;
 use dptree::di::DependencyMap;
 use dptree::Endpoint;
 use proc_qq::{
-    GroupMessageRecallEvent, GroupMessageRecallEventProcess, MessageEvent, MessageEventProcess,
-    Module, ModuleEventHandler, ModuleEventProcess, NewFriendRequestEvent,
-    NewFriendRequestEventProcess,
+    ConnectedAndOnlineEvent, ConnectedAndOnlineEventProcess, DeleteFriendEvent,
+    DeleteFriendEventProcess, DisconnectedAndOfflineEvent, DisconnectedAndOfflineEventProcess,
+    FriendMessageRecallEvent, FriendMessageRecallEventProcess, FriendPokeEvent,
+    FriendPokeEventProcess, GroupDisbandEvent, GroupDisbandEventProcess, GroupLeaveEvent,
+    GroupLeaveEventProcess, GroupMessageRecallEvent, GroupMessageRecallEventProcess,
+    GroupMuteEvent, GroupMuteEventProcess, GroupNameUpdateEvent, GroupNameUpdateEventProcess,
+    GroupRequestEvent, GroupRequestEventProcess, KickedOfflineEvent, KickedOfflineEventProcess,
+    MSFOfflineEvent, MSFOfflineEventProcess, MemberPermissionChangeEvent,
+    MemberPermissionChangeEventProcess, MessageEvent, MessageEventProcess, Module,
+    ModuleEventHandler, ModuleEventProcess, NewFriendEvent, NewFriendEventProcess,
+    NewFriendRequestEvent, NewFriendRequestEventProcess, NewMemberEvent, NewMemberEventProcess,
+    SelfInvitedEvent, SelfInvitedEventProcess,
 };
 
+use crate::client::Client;
+use crate::metrics;
+
 pub type EVHandler = Endpoint<'static, DependencyMap, Result<()>>;
 
+#[derive(Clone)]
 struct EventCollector {
     dp: DependencyMap,
     handler: EVHandler,
@@ -20,88 +33,144 @@ struct EventCollector {
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum UpdateKind {
     GroupMessage,
-    GroupMessageRecall,
     FriendMessage,
     GroupTempMessage,
+    GroupRequest,
+    SelfInvited,
     NewFriendRequest,
+    NewMember,
+    GroupMute,
+    FriendMessageRecall,
+    GroupMessageRecall,
+    NewFriend,
+    GroupLeave,
+    GroupDisband,
+    FriendPoke,
+    GroupNameUpdate,
+    DeleteFriend,
+    MemberPermissionChange,
+    KickedOffline,
+    MSFOffline,
+    ConnectedAndOnline,
+    DisconnectedAndOffline,
+}
+
+impl EventCollector {
+    async fn dispatch<E: Send + Sync + 'static>(
+        &self,
+        kind: UpdateKind,
+        client: Client,
+        event: E,
+    ) -> Result<bool> {
+        dispatch(&self.dp, &self.handler, kind, client, event).await?;
+        Ok(false)
+    }
+}
+
+/// Insert the event alongside its kind, the client that received it and the shared dependencies,
+/// then run the handler tree.
+pub async fn dispatch<E: Send + Sync + 'static>(
+    dp: &DependencyMap,
+    handler: &EVHandler,
+    kind: UpdateKind,
+    client: Client,
+    event: E,
+) -> Result<()> {
+    metrics::UPDATES_RECEIVED
+        .with_label_values(&[format!("{:?}", kind).as_str()])
+        .inc();
+    let mut dmap = DependencyMap::new();
+    dmap.insert(kind);
+    dmap.insert(client);
+    dmap.insert(event);
+    dmap.insert_container(dp.clone());
+    if let ControlFlow::Break(b) = handler.dispatch(dmap).await {
+        b?;
+    }
+    Ok(())
 }
 
 #[async_trait]
 impl MessageEventProcess for EventCollector {
     async fn handle(&self, event: &MessageEvent) -> Result<bool> {
-        let mut dmap = DependencyMap::new();
         match event {
             MessageEvent::GroupMessage(msg) => {
-                dmap.insert(UpdateKind::GroupMessage);
-                dmap.insert(msg.clone());
+                self.dispatch(UpdateKind::GroupMessage, msg.client.clone(), msg.clone())
+                    .await
             }
             MessageEvent::FriendMessage(msg) => {
-                dmap.insert(UpdateKind::FriendMessage);
-                dmap.insert(msg.clone());
+                self.dispatch(UpdateKind::FriendMessage, msg.client.clone(), msg.clone())
+                    .await
             }
             MessageEvent::GroupTempMessage(msg) => {
-                dmap.insert(UpdateKind::GroupTempMessage);
-                dmap.insert(msg.clone());
+                self.dispatch(
+                    UpdateKind::GroupTempMessage,
+                    msg.client.clone(),
+                    msg.clone(),
+                )
+                .await
             }
         }
-        dmap.insert_container(self.dp.clone());
-        if let ControlFlow::Break(b) = self.handler.dispatch(dmap).await {
-            b?;
-        }
-        Ok(false)
     }
 }
 
-#[async_trait]
-impl NewFriendRequestEventProcess for EventCollector {
-    async fn handle(&self, event: &NewFriendRequestEvent) -> Result<bool> {
-        let mut dmap = DependencyMap::new();
-        dmap.insert(UpdateKind::NewFriendRequest);
-        dmap.insert(event.clone());
-        dmap.insert_container(self.dp.clone());
-        if let ControlFlow::Break(b) = self.handler.dispatch(dmap).await {
-            b?;
+/// Implement the process trait of every listed event type by forwarding to `dispatch`, and build
+/// the matching list of `ModuleEventHandler`s.
+macro_rules! collect_events {
+    ($($kind:ident: $event:ty => $process:ident),* $(,)?) => {
+        $(
+            #[async_trait]
+            impl $process for EventCollector {
+                async fn handle(&self, event: &$event) -> Result<bool> {
+                    self.dispatch(UpdateKind::$kind, event.client.clone(), event.clone())
+                        .await
+                }
+            }
+        )*
+
+        fn event_handlers(collector: &EventCollector) -> Vec<ModuleEventHandler> {
+            vec![
+                ModuleEventHandler {
+                    name: "EventCollector".to_string(),
+                    process: ModuleEventProcess::Message(Box::new(collector.clone())),
+                },
+                $(
+                    ModuleEventHandler {
+                        name: "EventCollector".to_string(),
+                        process: ModuleEventProcess::$kind(Box::new(collector.clone())),
+                    },
+                )*
+            ]
         }
-        Ok(false)
-    }
+    };
 }
 
-#[async_trait]
-impl GroupMessageRecallEventProcess for EventCollector {
-    async fn handle(&self, event: &GroupMessageRecallEvent) -> Result<bool> {
-        let mut dmap = DependencyMap::new();
-        dmap.insert(UpdateKind::GroupMessageRecall);
-        dmap.insert(event.clone());
-        dmap.insert_container(self.dp.clone());
-        if let ControlFlow::Break(b) = self.handler.dispatch(dmap).await {
-            b?;
-        }
-        Ok(false)
-    }
+collect_events! {
+    GroupRequest: GroupRequestEvent => GroupRequestEventProcess,
+    SelfInvited: SelfInvitedEvent => SelfInvitedEventProcess,
+    NewFriendRequest: NewFriendRequestEvent => NewFriendRequestEventProcess,
+    NewMember: NewMemberEvent => NewMemberEventProcess,
+    GroupMute: GroupMuteEvent => GroupMuteEventProcess,
+    FriendMessageRecall: FriendMessageRecallEvent => FriendMessageRecallEventProcess,
+    GroupMessageRecall: GroupMessageRecallEvent => GroupMessageRecallEventProcess,
+    NewFriend: NewFriendEvent => NewFriendEventProcess,
+    GroupLeave: GroupLeaveEvent => GroupLeaveEventProcess,
+    GroupDisband: GroupDisbandEvent => GroupDisbandEventProcess,
+    FriendPoke: FriendPokeEvent => FriendPokeEventProcess,
+    GroupNameUpdate: GroupNameUpdateEvent => GroupNameUpdateEventProcess,
+    DeleteFriend: DeleteFriendEvent => DeleteFriendEventProcess,
+    MemberPermissionChange: MemberPermissionChangeEvent => MemberPermissionChangeEventProcess,
+    KickedOffline: KickedOfflineEvent => KickedOfflineEventProcess,
+    MSFOffline: MSFOfflineEvent => MSFOfflineEventProcess,
+    ConnectedAndOnline: ConnectedAndOnlineEvent => ConnectedAndOnlineEventProcess,
+    DisconnectedAndOffline: DisconnectedAndOfflineEvent => DisconnectedAndOfflineEventProcess,
 }
 
 pub fn module(dp: DependencyMap, handler: EVHandler) -> Module {
-    let on_message = ModuleEventHandler {
-        name: "EventCollector".to_string(),
-        process: ModuleEventProcess::Message(Box::new(EventCollector {
-            dp: dp.clone(),
-            handler: handler.clone(),
-        })),
-    };
-    let on_new_friend = ModuleEventHandler {
-        name: "EventCollector".to_string(),
-        process: ModuleEventProcess::NewFriendRequest(Box::new(EventCollector {
-            dp: dp.clone(),
-            handler: handler.clone(),
-        })),
-    };
-    let on_recall = ModuleEventHandler {
-        name: "EventCollector".to_string(),
-        process: ModuleEventProcess::GroupMessageRecall(Box::new(EventCollector { dp, handler })),
-    };
+    let collector = EventCollector { dp, handler };
     Module {
         id: "dp_handler".to_string(),
         name: "DI Adaptor".to_string(),
-        handles: vec![on_message, on_new_friend, on_recall],
+        handles: event_handlers(&collector),
     }
 }
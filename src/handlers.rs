@@ -2,40 +2,67 @@ use dptree::case;
 
 use crate::dp_helper::EVHandler;
 use crate::handlers::admin::{join_handler, request_otp_handler};
+use crate::handlers::approval::{approve_handler, deny_handler, join_request_handler};
 use crate::handlers::auth::Given;
 use crate::handlers::cluster::cluster_handler;
 use crate::handlers::forwarder::forwarder;
+use crate::handlers::help::help_handler;
+use crate::handlers::membership::{drop_duplicates, membership_handler};
 use crate::handlers::new_friend::new_friend_handler;
 use crate::handlers::parser::{parse_cmd, ClusterCommand, Command};
+use crate::handlers::presence::presence_handler;
+use crate::status::BotStatus;
 
 mod admin;
+pub mod approval;
 pub mod auth;
 mod cluster;
 mod forwarder;
 mod guard;
+mod help;
+mod membership;
 mod new_friend;
 mod parser;
+mod presence;
+#[cfg(test)]
+mod tests;
 
 pub fn handler() -> EVHandler {
-    dptree::entry()
-        .branch(new_friend_handler())
-        .branch(
-            dptree::entry().chain(parse_cmd(
-                dptree::entry()
-                    .branch(case![Command::RequestOTP { token }].chain(request_otp_handler()))
-                    .branch(
-                        case![Command::Cluster { cmd, token }]
-                            .map(|(cmd, _): (ClusterCommand, Given)| cmd)
-                            .map(|(_, given): (ClusterCommand, Given)| given)
-                            .chain(cluster_handler()),
-                    )
-                    .branch(
-                        case![Command::Join { cluster, otp }]
-                            .map(|(cluster, _): (String, Given)| cluster)
-                            .map(|(_, given): (String, Given)| given)
-                            .chain(join_handler()),
-                    ),
-            )),
-        )
-        .branch(forwarder())
+    dptree::filter(|status: BotStatus| {
+        status.record_event();
+        true
+    })
+    .branch(presence_handler())
+    .branch(membership_handler())
+    .branch(drop_duplicates())
+    .branch(new_friend_handler())
+    .branch(
+        dptree::entry().chain(parse_cmd(
+            dptree::entry()
+                .branch(case![Command::Help].chain(help_handler()))
+                .branch(case![Command::RequestOTP { token }].chain(request_otp_handler()))
+                .branch(
+                    case![Command::Cluster { cmd, token }]
+                        .map(|(cmd, _): (ClusterCommand, Option<Given>)| cmd)
+                        .map(|(_, given): (ClusterCommand, Option<Given>)| given)
+                        .chain(cluster_handler()),
+                )
+                .branch(
+                    case![Command::Join { cluster, otp }]
+                        .map(|(cluster, _): (String, Option<Given>)| cluster)
+                        .branch(
+                            dptree::filter_map(|(_, otp): (String, Option<Given>)| otp)
+                                .chain(join_handler()),
+                        )
+                        // A wrong one-time password must not fall through to a join request.
+                        .branch(
+                            dptree::filter(|(_, otp): (String, Option<Given>)| otp.is_none())
+                                .chain(join_request_handler()),
+                        ),
+                )
+                .branch(case![Command::Approve { id }].chain(approve_handler()))
+                .branch(case![Command::Deny { id }].chain(deny_handler())),
+        )),
+    )
+    .branch(forwarder())
 }
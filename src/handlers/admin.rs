@@ -1,9 +1,8 @@
 use dptree::case;
-use proc_qq::{
-    FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait, MessageSendToSourceTrait,
-};
+use proc_qq::{FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait};
 use tracing::{info, warn};
 
+use crate::client::{Client, Reply};
 use crate::db::{Group, DB};
 use crate::dp_helper::EVHandler;
 use crate::dp_helper::UpdateKind;
@@ -12,12 +11,15 @@ use crate::handlers::guard::must_admin;
 
 pub fn request_otp_handler() -> EVHandler {
     case![UpdateKind::FriendMessage].chain(token_auth(dptree::endpoint(
-        |ev: FriendMessageEvent, otp: OTP| async move {
-            let pass = otp.generate_new();
-            ev.send_message_to_source(
-                format!("Your one-time password is:\n{}", pass).parse_message_chain(),
-            )
-            .await?;
+        |client: Client, ev: FriendMessageEvent, otp: OTP| async move {
+            let msg = match otp.generate_new().await {
+                Ok(pass) => format!("Your one-time password is:\n{}", pass),
+                Err(e) => {
+                    warn!(?e, "failed to issue one-time password");
+                    "Failed to issue one-time password. Please try again later.".into()
+                }
+            };
+            ev.reply(&client, msg.parse_message_chain()).await?;
             Ok(())
         },
     )))
@@ -28,7 +30,7 @@ pub fn join_handler() -> EVHandler {
         .chain(must_admin())
         .chain(otp_auth(dptree::endpoint(
             // TODO earlier: extract join name as string
-            |db: DB, cluster: String, ev: GroupMessageEvent| async move {
+            |db: DB, cluster: String, client: Client, ev: GroupMessageEvent| async move {
                 let group = Group::from_qq(ev.inner.group_code);
                 let msg = match db.join(&cluster, &group).await {
                     Ok(_) => {
@@ -40,7 +42,7 @@ pub fn join_handler() -> EVHandler {
                         "Failed to join cluster. Please try again later."
                     }
                 };
-                ev.send_message_to_source(msg.parse_message_chain()).await?;
+                ev.reply(&client, msg.parse_message_chain()).await?;
                 Ok(())
             },
         )))
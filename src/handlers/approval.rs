@@ -0,0 +1,268 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use dptree::case;
+use proc_qq::{FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait};
+use tracing::{info, warn};
+
+use crate::accounts::Accounts;
+use crate::client::{Client, Reply};
+use crate::config::Config;
+use crate::db::{Group, DB};
+use crate::dp_helper::{EVHandler, UpdateKind};
+use crate::handlers::guard::must_admin;
+
+#[derive(Debug, Clone)]
+pub struct JoinRequest {
+    pub cluster: String,
+    pub group: i64,
+    pub group_name: String,
+    pub requester: i64,
+    created: Instant,
+}
+
+/// Pending requests of groups to join clusters, waiting for approval by the cluster owner or admins.
+#[derive(Debug, Clone)]
+pub struct JoinRequests {
+    requests: Arc<DashMap<u32, JoinRequest>>,
+    next_id: Arc<AtomicU32>,
+    ttl: Duration,
+}
+
+impl JoinRequests {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            requests: Arc::default(),
+            next_id: Arc::new(AtomicU32::new(1)),
+            ttl,
+        }
+    }
+    fn expire(&self) {
+        self.requests
+            .retain(|_, req| req.created.elapsed() < self.ttl);
+    }
+    /// Record a new request and return its id. A previous request of the same group to the same
+    /// cluster is replaced.
+    pub fn insert(&self, cluster: String, group: i64, group_name: String, requester: i64) -> u32 {
+        self.expire();
+        self.requests
+            .retain(|_, req| !(req.cluster == cluster && req.group == group));
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.requests.insert(
+            id,
+            JoinRequest {
+                cluster,
+                group,
+                group_name,
+                requester,
+                created: Instant::now(),
+            },
+        );
+        id
+    }
+    pub fn get(&self, id: u32) -> Option<JoinRequest> {
+        self.expire();
+        self.requests.get(&id).map(|req| req.clone())
+    }
+    pub fn remove(&self, id: u32) -> Option<JoinRequest> {
+        self.requests.remove(&id).map(|(_, req)| req)
+    }
+    pub const fn ttl(&self) -> Duration {
+        self.ttl
+    }
+}
+
+pub fn join_request_handler() -> EVHandler {
+    case![UpdateKind::GroupMessage].chain(must_admin()).endpoint(
+        |db: DB,
+         requests: JoinRequests,
+         config: Arc<Config>,
+         cluster: String,
+         client: Client,
+         ev: GroupMessageEvent| async move {
+            let managers: Vec<i64> = match db.cluster(&cluster).await? {
+                Some(found) => found.managers().collect(),
+                None => {
+                    ev.reply(&client, "No such cluster.".parse_message_chain())
+                        .await?;
+                    return Ok(());
+                }
+            };
+            if managers.is_empty() {
+                ev.reply(&client,
+                    "This cluster has no owner to approve join requests. \
+                    Please ask an operator for a one-time password."
+                        .parse_message_chain(),
+                )
+                .await?;
+                return Ok(());
+            }
+            let group = ev.inner.group_code;
+            let id = requests.insert(
+                cluster.clone(),
+                group,
+                ev.inner.group_name.clone(),
+                ev.inner.from_uin,
+            );
+            let notice = format!(
+                "Group {} ({}) requests to join cluster {}.\nApprove: {}approve {}\nDeny: {}deny {}",
+                ev.inner.group_name, group, cluster, config.prefix, id, config.prefix, id
+            );
+            let mut notified = 0;
+            for manager in managers {
+                match client
+                    .send_friend_message(manager, notice.parse_message_chain())
+                    .await
+                {
+                    Ok(_) => notified += 1,
+                    Err(e) => warn!(?e, manager, "failed to notify cluster manager"),
+                }
+            }
+            let msg = if notified > 0 {
+                info!(id, group, cluster, "join request created");
+                format!(
+                    "Join request sent to the cluster owner or admins. It expires in {}.",
+                    describe_ttl(requests.ttl())
+                )
+            } else {
+                requests.remove(id);
+                "Failed to notify the cluster owner or admins. Please try again later.".into()
+            };
+            ev.reply(&client, msg.parse_message_chain()).await?;
+            Ok(())
+        },
+    )
+}
+
+/// Render a request lifetime in whole minutes, or in seconds if shorter than a minute.
+fn describe_ttl(ttl: Duration) -> String {
+    match ttl.as_secs() {
+        secs if secs < 60 => format!("{} seconds", secs),
+        secs => format!("{} minutes", secs / 60),
+    }
+}
+
+/// Resolve a pending request the sender is allowed to decide on, replying with the reason if
+/// there is none.
+async fn decidable_request(
+    db: &DB,
+    client: &Client,
+    requests: &JoinRequests,
+    id: u32,
+    ev: &FriendMessageEvent,
+) -> anyhow::Result<Option<JoinRequest>> {
+    let Some(req) = requests.get(id) else {
+        ev.reply(client, "No such join request, or it has expired.".parse_message_chain())
+            .await?;
+        return Ok(None);
+    };
+    let role = db
+        .cluster(&req.cluster)
+        .await?
+        .and_then(|c| c.role_of(ev.inner.from_uin));
+    if role.is_none() {
+        ev.reply(
+            client,
+            "Only the cluster owner and admins can decide on this request.".parse_message_chain(),
+        )
+        .await?;
+        return Ok(None);
+    }
+    Ok(requests.remove(id))
+}
+
+pub fn approve_handler() -> EVHandler {
+    case![UpdateKind::FriendMessage].endpoint(
+        |db: DB,
+         accounts: Accounts,
+         requests: JoinRequests,
+         id: u32,
+         client: Client,
+         ev: FriendMessageEvent| async move {
+            let Some(req) = decidable_request(&db, &client, &requests, id, &ev).await? else {
+                return Ok(());
+            };
+            let group = Group::from_qq(req.group);
+            let (reply, notice) = match db.join(&req.cluster, &group).await {
+                Ok(_) => {
+                    info!(
+                        ?group,
+                        cluster = req.cluster,
+                        requester = req.requester,
+                        "group joined cluster"
+                    );
+                    (
+                        format!("Group {} joined cluster {}.", req.group_name, req.cluster),
+                        Some("Join request approved. Joined to cluster"),
+                    )
+                }
+                Err(e) => {
+                    warn!(?e, "failed to join cluster");
+                    (
+                        "Failed to join cluster. Please try again later.".to_string(),
+                        None,
+                    )
+                }
+            };
+            if let Some(notice) = notice {
+                if let Err(e) = accounts
+                    .send_group_message(req.group, notice.parse_message_chain())
+                    .await
+                {
+                    warn!(?e, group = req.group, "failed to notify group");
+                }
+            }
+            ev.reply(&client, reply.parse_message_chain()).await?;
+            Ok(())
+        },
+    )
+}
+
+pub fn deny_handler() -> EVHandler {
+    case![UpdateKind::FriendMessage].endpoint(
+        |db: DB,
+         accounts: Accounts,
+         requests: JoinRequests,
+         id: u32,
+         client: Client,
+         ev: FriendMessageEvent| async move {
+            let Some(req) = decidable_request(&db, &client, &requests, id, &ev).await? else {
+                return Ok(());
+            };
+            info!(
+                id,
+                group = req.group,
+                cluster = req.cluster,
+                "join request denied"
+            );
+            if let Err(e) = accounts
+                .send_group_message(
+                    req.group,
+                    format!("Request to join cluster {} was denied.", req.cluster)
+                        .parse_message_chain(),
+                )
+                .await
+            {
+                warn!(?e, group = req.group, "failed to notify group");
+            }
+            ev.reply(&client, "Join request denied.".parse_message_chain())
+                .await?;
+            Ok(())
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::describe_ttl;
+
+    #[test]
+    fn short_ttls_are_shown_in_seconds() {
+        assert_eq!(describe_ttl(Duration::from_secs(30)), "30 seconds");
+        assert_eq!(describe_ttl(Duration::from_secs(600)), "10 minutes");
+    }
+}
@@ -2,25 +2,27 @@ use std::convert::Infallible;
 use std::fmt::Display;
 use std::ops::Deref;
 use std::str::FromStr;
-use std::sync::Arc;
 
+use anyhow::Result;
 use chbs::config::BasicConfig;
 use chbs::probability::Probability;
 use chbs::scheme::ToScheme;
 use chbs::word::{WordList, WordSampler};
-use dashmap::DashSet;
 use dptree::case;
 use internment::Intern;
-use proc_qq::{
-    FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait, MessageSendToSourceTrait,
-};
+use proc_qq::{FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait};
+use tracing::{error, info};
 
+use crate::client::{Client, Reply};
+use crate::db::{ClusterRole, DB};
 use crate::dp_helper::{EVHandler, UpdateKind};
+use crate::metrics;
 
+/// One-time passwords, kept in the storage so that ones issued from the command line work too.
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone, Default)]
+#[derive(Clone)]
 pub struct OTP {
-    otps: Arc<DashSet<String>>,
+    db: DB,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -65,14 +67,58 @@ pub fn random_pass() -> String {
     scheme.generate()
 }
 
+/// Compare a given secret in time independent of where it differs, so that it can not be guessed
+/// byte by byte from response times.
+pub fn secret_eq(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+impl Token {
+    /// The token saved in the storage, or a new one saved there if there is none. Only a new token
+    /// is logged, so that the credential does not end up in every log.
+    pub async fn load(db: &DB) -> Result<Self> {
+        Ok(match db.token().await? {
+            Some(saved) => Self(Intern::new(saved)),
+            None => {
+                let token = Self::rotate(db).await?;
+                info!(
+                    "Created the manage token {}. It is not shown again, use `token rotate` to \
+                    replace it if lost.",
+                    token
+                );
+                token
+            }
+        })
+    }
+    /// Replace the saved token with a new one. Running bots keep using the old one until restarted.
+    pub async fn rotate(db: &DB) -> Result<Self> {
+        let token = Self::default();
+        db.set_token(&token).await?;
+        Ok(token)
+    }
+}
+
 impl OTP {
-    pub fn generate_new(&self) -> String {
+    pub const fn new(db: DB) -> Self {
+        Self { db }
+    }
+    pub async fn generate_new(&self) -> Result<String> {
         let pass = random_pass();
-        self.otps.insert(pass.clone());
-        pass
+        self.db.add_otp(&pass).await?;
+        metrics::OTP_ISSUED.inc();
+        Ok(pass)
     }
-    pub fn verify(&self, pass: &str) -> bool {
-        self.otps.remove(pass).is_some()
+    pub async fn verify(&self, pass: &str) -> Result<bool> {
+        let valid = self.db.take_otp(pass).await?;
+        metrics::OTP_VERIFIED
+            .with_label_values(&[if valid { "valid" } else { "invalid" }])
+            .inc();
+        Ok(valid)
     }
 }
 
@@ -92,12 +138,12 @@ pub fn token_auth(authed: EVHandler) -> EVHandler {
         .branch(
             case![UpdateKind::FriendMessage]
                 .filter_async(
-                    |Given(given), token: Token, ev: FriendMessageEvent| async move {
-                        if given == token.as_ref() {
+                    |Given(given), token: Token, client: Client, ev: FriendMessageEvent| async move {
+                        if secret_eq(&given, &token) {
                             true
                         } else {
                             drop(
-                                ev.send_message_to_source("Invalid token".parse_message_chain())
+                                ev.reply(&client, "Invalid token".parse_message_chain())
                                     .await,
                             );
                             false
@@ -109,12 +155,12 @@ pub fn token_auth(authed: EVHandler) -> EVHandler {
         .branch(
             case![UpdateKind::GroupMessage]
                 .filter_async(
-                    |Given(given), token: Token, ev: GroupMessageEvent| async move {
-                        if given == token.as_ref() {
+                    |Given(given), token: Token, client: Client, ev: GroupMessageEvent| async move {
+                        if secret_eq(&given, &token) {
                             true
                         } else {
                             drop(
-                                ev.send_message_to_source("Invalid token".parse_message_chain())
+                                ev.reply(&client, "Invalid token".parse_message_chain())
                                     .await,
                             );
                             false
@@ -130,37 +176,119 @@ pub fn otp_auth(authed: EVHandler) -> EVHandler {
         .branch(
             case![UpdateKind::FriendMessage]
                 .filter_async(
-                    |Given(given), otp: OTP, ev: FriendMessageEvent| async move {
-                        if otp.verify(&given) {
-                            true
-                        } else {
+                    |Given(given), otp: OTP, client: Client, ev: FriendMessageEvent| async move {
+                        let valid = otp.verify(&given).await.unwrap_or_else(|e| {
+                            error!(?e, "failed to verify one-time password");
+                            false
+                        });
+                        if !valid {
                             drop(
-                                ev.send_message_to_source(
+                                ev.reply(
+                                    &client,
                                     "Invalid one-time password".parse_message_chain(),
                                 )
                                 .await,
                             );
-                            false
                         }
+                        valid
                     },
                 )
                 .chain(authed.clone()),
         )
         .branch(
             case![UpdateKind::GroupMessage]
-                .filter_async(|Given(given), otp: OTP, ev: GroupMessageEvent| async move {
-                    if otp.verify(&given) {
-                        true
-                    } else {
+                .filter_async(
+                    |Given(given), otp: OTP, client: Client, ev: GroupMessageEvent| async move {
+                        let valid = otp.verify(&given).await.unwrap_or_else(|e| {
+                            error!(?e, "failed to verify one-time password");
+                            false
+                        });
+                        if !valid {
+                            drop(
+                                ev.reply(
+                                    &client,
+                                    "Invalid one-time password".parse_message_chain(),
+                                )
+                                .await,
+                            );
+                        }
+                        valid
+                    },
+                )
+                .chain(authed),
+        )
+}
+
+/// Turn an optional token into a required one, telling the user if it's missing.
+pub fn require_token() -> EVHandler {
+    case![UpdateKind::FriendMessage].filter_map_async(
+        |given: Option<Given>, client: Client, ev: FriendMessageEvent| async move {
+            if given.is_none() {
+                drop(
+                    ev.reply(
+                        &client,
+                        "This command requires a token.".parse_message_chain(),
+                    )
+                    .await,
+                );
+            }
+            given
+        },
+    )
+}
+
+/// Let through users holding the management token, or having at least `required` role in the
+/// cluster the command targets.
+pub fn cluster_auth(required: ClusterRole, authed: EVHandler) -> EVHandler {
+    case![UpdateKind::FriendMessage]
+        .filter_async(
+            move |given: Option<Given>,
+                  token: Token,
+                  cluster: String,
+                  db: DB,
+                  client: Client,
+                  ev: FriendMessageEvent| async move {
+                let role = match given {
+                    Some(Given(given)) if secret_eq(&given, &token) => Some(ClusterRole::Operator),
+                    Some(_) => {
                         drop(
-                            ev.send_message_to_source(
-                                "Invalid one-time password".parse_message_chain(),
-                            )
-                            .await,
+                            ev.reply(&client, "Invalid token".parse_message_chain())
+                                .await,
                         );
-                        false
+                        return false;
                     }
-                })
-                .chain(authed),
+                    None => match db.cluster(&cluster).await {
+                        Ok(Some(found)) => found.role_of(ev.inner.from_uin),
+                        Ok(None) => {
+                            drop(
+                                ev.reply(&client, "No such cluster.".parse_message_chain())
+                                    .await,
+                            );
+                            return false;
+                        }
+                        Err(e) => {
+                            error!(cluster, ?e, "failed to get cluster");
+                            drop(
+                                ev.reply(
+                                    &client,
+                                    "Failed to authenticate user.".parse_message_chain(),
+                                )
+                                .await,
+                            );
+                            return false;
+                        }
+                    },
+                };
+                if role >= Some(required) {
+                    true
+                } else {
+                    drop(
+                        ev.reply(&client, "Permission denied.".parse_message_chain())
+                            .await,
+                    );
+                    false
+                }
+            },
         )
+        .chain(authed)
 }
@@ -1,45 +1,280 @@
 use dptree::case;
 use itertools::Itertools;
-use proc_qq::{FriendMessageEvent, MessageChainParseTrait, MessageSendToSourceTrait};
+use proc_qq::{FriendMessageEvent, MessageChainParseTrait};
 use tracing::{info, warn};
 
-use crate::db::DB;
+use crate::accounts::Accounts;
+use crate::backup::{self, Backup, Format, PartialImport};
+use crate::bridge::Backends;
+use crate::client::{Client, Reply};
+use crate::db::{ClusterRole, Group, DB, IM};
 use crate::dp_helper::EVHandler;
 use crate::dp_helper::UpdateKind;
-use crate::handlers::auth::token_auth;
+use crate::handlers::auth::{cluster_auth, require_token, token_auth};
 use crate::handlers::parser::ClusterCommand;
 
+/// Longest export sent as a message. Larger ones are likely to be cut off or rejected.
+const MAX_EXPORT_CHARS: usize = 4000;
+
 pub fn cluster_handler() -> EVHandler {
-    dptree::entry().branch(
-        case![UpdateKind::FriendMessage].chain(token_auth(
-            dptree::entry()
-                .branch(case![ClusterCommand::List].endpoint(
-                    |db: DB, ev: FriendMessageEvent| async move {
-                        let clusters = db.clusters().await?.join("\n");
-                        ev.send_message_to_source(
-                            format!("Available clusters:\n{}", clusters).parse_message_chain(),
-                        )
-                        .await?;
+    dptree::entry()
+        .branch(
+            case![UpdateKind::FriendMessage]
+                .branch(
+                    case![ClusterCommand::List]
+                        .chain(require_token())
+                        .chain(token_auth(dptree::endpoint(
+                            |db: DB, client: Client, ev: FriendMessageEvent| async move {
+                                let clusters = db.clusters().await?.join("\n");
+                                ev.reply(&client,
+                                    format!("Available clusters:\n{}", clusters)
+                                        .parse_message_chain(),
+                                )
+                                .await?;
+                                Ok(())
+                            },
+                        ))),
+                )
+                .branch(
+                    case![ClusterCommand::Add]
+                        .chain(require_token())
+                        .chain(token_auth(dptree::endpoint(
+                            |db: DB, client: Client, ev: FriendMessageEvent| async move {
+                                let msg = match db.new_cluster(Some(ev.inner.from_uin)).await {
+                                    Ok(name) => {
+                                        info!(name, "new cluster created");
+                                        format!("New cluster created: {}", name)
+                                    }
+                                    Err(e) => {
+                                        warn!(?e, "failed to create new cluster");
+                                        "Failed to create cluster. Please try again later.".into()
+                                    }
+                                };
+                                ev.reply(&client, msg.parse_message_chain()).await?;
+                                Ok(())
+                            },
+                        ))),
+                )
+                .branch(
+                    case![ClusterCommand::Export { format }]
+                        .chain(require_token())
+                        .chain(token_auth(dptree::endpoint(
+                            |db: DB, format: Format, client: Client, ev: FriendMessageEvent| async move {
+                                let exported = backup::export(&db).await.and_then(|backup| {
+                                    let text = backup.render(format)?;
+                                    Ok((backup, text))
+                                });
+                                let msg = match exported {
+                                    Ok((_, text)) if text.chars().count() <= MAX_EXPORT_CHARS => text,
+                                    Ok((backup, _)) => format!(
+                                        "The export of {} clusters with {} groups is too large to send here. \
+                                        Please use the export command of the command line instead.",
+                                        backup.clusters.len(),
+                                        backup.clusters.iter().map(|cluster| cluster.groups.len()).sum::<usize>()
+                                    ),
+                                    Err(e) => {
+                                        warn!(?e, "failed to export clusters");
+                                        "Failed to export clusters. Please try again later.".into()
+                                    }
+                                };
+                                ev.reply(&client, msg.parse_message_chain()).await?;
+                                Ok(())
+                            },
+                        ))),
+                )
+                .branch(
+                    case![ClusterCommand::Import { document }]
+                        .chain(require_token())
+                        .chain(token_auth(dptree::endpoint(
+                            |db: DB, document: String, client: Client, ev: FriendMessageEvent| async move {
+                                let msg = match Backup::parse(&document) {
+                                    Ok(parsed) => match backup::import(&db, parsed).await {
+                                        Ok(imported) => {
+                                            info!(?imported, "clusters imported");
+                                            format!("Clusters imported: {}.", imported)
+                                        }
+                                        Err(e) => {
+                                            warn!(?e, "failed to import clusters");
+                                            match e.downcast_ref::<PartialImport>() {
+                                                Some(partial) => format!(
+                                                    "Failed to import cluster {}. Clusters before it were \
+                                                    imported: {}. Please try again later.",
+                                                    partial.cluster, partial.imported
+                                                ),
+                                                None => "Failed to import clusters. Please try again later."
+                                                    .into(),
+                                            }
+                                        }
+                                    },
+                                    Err(e) => format!("{:#}", e),
+                                };
+                                ev.reply(&client, msg.parse_message_chain()).await?;
+                                Ok(())
+                            },
+                        ))),
+                )
+                .branch(case![ClusterCommand::Show { cluster }].chain(cluster_auth(
+                    ClusterRole::Admin,
+                    dptree::endpoint(|db: DB, cluster: String, client: Client, ev: FriendMessageEvent| async move {
+                        let msg = match db.cluster(&cluster).await? {
+                            Some(found) => format!(
+                                "Cluster: {}\nOwner: {}\nAdmins: {}\nGroups:\n{}",
+                                found.name,
+                                found
+                                    .owner
+                                    .map_or_else(|| "(none)".to_string(), |o| o.to_string()),
+                                found.admins.iter().join(", "),
+                                found
+                                    .groups
+                                    .iter()
+                                    .map(|group| format!("{:?} {}", group.im, group.id))
+                                    .join("\n")
+                            ),
+                            None => "No such cluster.".to_string(),
+                        };
+                        ev.reply(&client, msg.parse_message_chain()).await?;
                         Ok(())
-                    },
-                ))
-                .branch(case![ClusterCommand::Add].endpoint(
-                    |db: DB, ev: FriendMessageEvent| async move {
-                        let msg = match db.new_cluster().await {
-                            Ok(name) => {
-                                info!(name, "new cluster created");
-                                format!("New cluster created: {}", name)
+                    }),
+                )))
+                .branch(case![ClusterCommand::Delete { cluster }].chain(cluster_auth(
+                    ClusterRole::Owner,
+                    dptree::endpoint(|db: DB, cluster: String, client: Client, ev: FriendMessageEvent| async move {
+                        let msg = match db.delete_cluster(&cluster).await {
+                            Ok(_) => {
+                                info!(cluster, "cluster deleted");
+                                "Cluster deleted."
                             }
                             Err(e) => {
-                                warn!(?e, "failed to create new cluster");
-                                "Failed to create cluster. Please try again later.".into()
+                                warn!(?e, "failed to delete cluster");
+                                "Failed to delete cluster. Please try again later."
                             }
                         };
-                        ev.send_message_to_source(msg.parse_message_chain()).await?;
+                        ev.reply(&client, msg.parse_message_chain()).await?;
                         Ok(())
-                    },
-                )),
-        )),
-    )
-    // TODO delete cluster
+                    }),
+                )))
+                .branch(
+                    case![ClusterCommand::Transfer { cluster, owner }]
+                        .map(|(cluster, _): (String, i64)| cluster)
+                        .map(|(_, owner): (String, i64)| owner)
+                        .chain(cluster_auth(
+                            ClusterRole::Owner,
+                            dptree::endpoint(
+                                |db: DB, cluster: String, owner: i64, client: Client, ev: FriendMessageEvent| async move {
+                                    let msg = match db.set_owner(&cluster, owner).await {
+                                        Ok(_) => {
+                                            info!(cluster, owner, "cluster ownership transferred");
+                                            format!("Cluster is now owned by {}.", owner)
+                                        }
+                                        Err(e) => {
+                                            warn!(?e, "failed to transfer cluster");
+                                            "Failed to transfer cluster. Please try again later."
+                                                .into()
+                                        }
+                                    };
+                                    ev.reply(&client, msg.parse_message_chain()).await?;
+                                    Ok(())
+                                },
+                            ),
+                        )),
+                )
+                .branch(
+                    case![ClusterCommand::AddAdmin { cluster, uin }]
+                        .map(|(cluster, _): (String, i64)| cluster)
+                        .map(|(_, uin): (String, i64)| uin)
+                        .chain(cluster_auth(
+                            ClusterRole::Owner,
+                            dptree::endpoint(
+                                |db: DB, cluster: String, uin: i64, client: Client, ev: FriendMessageEvent| async move {
+                                    let msg = match db.add_admin(&cluster, uin).await {
+                                        Ok(_) => {
+                                            info!(cluster, uin, "cluster admin added");
+                                            format!("{} is now an admin of the cluster.", uin)
+                                        }
+                                        Err(e) => {
+                                            warn!(?e, "failed to add cluster admin");
+                                            "Failed to add admin. Please try again later.".into()
+                                        }
+                                    };
+                                    ev.reply(&client, msg.parse_message_chain()).await?;
+                                    Ok(())
+                                },
+                            ),
+                        )),
+                )
+                .branch(
+                    case![ClusterCommand::RemoveAdmin { cluster, uin }]
+                        .map(|(cluster, _): (String, i64)| cluster)
+                        .map(|(_, uin): (String, i64)| uin)
+                        .chain(cluster_auth(
+                            ClusterRole::Owner,
+                            dptree::endpoint(
+                                |db: DB, cluster: String, uin: i64, client: Client, ev: FriendMessageEvent| async move {
+                                    let msg = match db.remove_admin(&cluster, uin).await {
+                                        Ok(_) => {
+                                            info!(cluster, uin, "cluster admin removed");
+                                            format!("{} is no longer an admin of the cluster.", uin)
+                                        }
+                                        Err(e) => {
+                                            warn!(?e, "failed to remove cluster admin");
+                                            "Failed to remove admin. Please try again later."
+                                                .into()
+                                        }
+                                    };
+                                    ev.reply(&client, msg.parse_message_chain()).await?;
+                                    Ok(())
+                                },
+                            ),
+                        )),
+                )
+                .branch(
+                    case![ClusterCommand::Kick { cluster, im, id, notify }]
+                        .map(|(cluster, ..): (String, IM, String, bool)| cluster)
+                        .chain(cluster_auth(
+                            ClusterRole::Owner,
+                            dptree::endpoint(
+                                |db: DB,
+                                 accounts: Accounts,
+                                 backends: Backends,
+                                 (cluster, im, id, notify): (String, IM, String, bool),
+                                 client: Client,
+                                 ev: FriendMessageEvent| async move {
+                                    let group = Group { im, id };
+                                    let msg = match db.cluster(&cluster).await {
+                                        Ok(Some(found)) if !found.groups.contains(&group) => {
+                                            "That group is not in the cluster."
+                                        }
+                                        Ok(None) => "No such cluster.",
+                                        Ok(Some(_)) => match db.leave(&cluster, &group).await {
+                                            Ok(_) => {
+                                                info!(?group, cluster, "group kicked from cluster");
+                                                if notify {
+                                                    kick_notice(&accounts, &backends, &group, &cluster).await;
+                                                }
+                                                "Group kicked from cluster."
+                                            }
+                                            Err(e) => {
+                                                warn!(?e, "failed to kick group");
+                                                "Failed to kick group. Please try again later."
+                                            }
+                                        },
+                                        Err(e) => {
+                                            warn!(?e, "failed to get cluster");
+                                            "Failed to kick group. Please try again later."
+                                        }
+                                    };
+                                    ev.reply(&client, msg.parse_message_chain()).await?;
+                                    Ok(())
+                                },
+                            ),
+                        )),
+                ),
+        )
+}
+
+async fn kick_notice(accounts: &Accounts, backends: &Backends, group: &Group, cluster: &str) {
+    let notice = format!("This group has been removed from cluster {}.", cluster);
+    if let Err(e) = backends.send_notice(accounts, group, &notice).await {
+        warn!(?e, ?group, "failed to notify kicked group");
+    }
 }
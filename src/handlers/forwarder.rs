@@ -1,45 +1,79 @@
-use std::sync::Arc;
-
-use anyhow::Result;
-use dptree::case;
-use proc_qq::re_exports::ricq;
+use anyhow::{anyhow, Result};
 use proc_qq::re_exports::ricq::msg::elem::RQElem;
 use proc_qq::re_exports::ricq::structs::GroupMessage;
 use proc_qq::{GroupMessageEvent, MessageChainParseTrait};
 use tracing::error;
 
+use crate::accounts::Accounts;
+use crate::bridge::{Backends, BridgedMessage};
+use crate::client::Client;
 use crate::db::{Group, DB, IM};
-use crate::dp_helper::{EVHandler, UpdateKind};
+use crate::dp_helper::EVHandler;
+use crate::handlers::guard::must_bridgeable;
+use crate::metrics;
+use crate::status::ForwardStats;
 
 pub fn forwarder() -> EVHandler {
-    case![UpdateKind::GroupMessage].endpoint(|db: DB, ev: GroupMessageEvent| async move {
-        let group = ev.inner.group_code;
-        let client = ev.client;
-        let targets = db.forward_targets(&Group::from_qq(group)).await?;
-        // TODO should have better logic separation (e.g. a special object for unified tg/qq forward)
-        for target in targets {
-            let client = client.clone();
-            let msg = ev.inner.clone();
-            tokio::spawn(async move {
-                if let Err(e) = forward(client, msg, target.clone()).await {
-                    error!(?e, ?group, "failed to forward message");
-                }
-            });
-        }
-        Ok(())
-    })
+    must_bridgeable().endpoint(
+        |db: DB,
+         accounts: Accounts,
+         backends: Backends,
+         stats: ForwardStats,
+         client: Client,
+         ev: GroupMessageEvent| async move {
+            let group = ev.inner.group_code;
+            let source = Group::from_qq(group);
+            let targets = {
+                let _timer = metrics::FORWARD_TARGETS_QUERY.start_timer();
+                db.forward_targets(&source).await?
+            };
+            // TODO should have better logic separation (e.g. a special object for unified tg/qq forward)
+            for target in targets {
+                let client = client.clone();
+                let accounts = accounts.clone();
+                let backends = backends.clone();
+                let msg = ev.inner.clone();
+                let source = source.clone();
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    let timer = metrics::FORWARD_LATENCY.start_timer();
+                    let result = forward(&accounts, &backends, client, msg, target.clone()).await;
+                    timer.observe_duration();
+                    match result {
+                        Ok(()) => {
+                            stats.record_delivered();
+                            metrics::MESSAGES_FORWARDED
+                                .with_label_values(&[format!("{:?}", target.im).as_str()])
+                                .inc();
+                        }
+                        Err(e) => {
+                            error!(?e, ?group, "failed to forward message");
+                            metrics::FORWARD_FAILURES
+                                .with_label_values(&[metrics::error_class(&e).as_str()])
+                                .inc();
+                            stats.record_failure(source, target, &e);
+                        }
+                    }
+                });
+            }
+            Ok(())
+        },
+    )
 }
 
-async fn forward(client: Arc<ricq::Client>, msg: GroupMessage, group: Group) -> Result<()> {
+/// Forward a message to the target group.
+///
+/// `client` is the account that received the message; the copy is sent by the account serving the
+/// target group.
+async fn forward(
+    accounts: &Accounts,
+    backends: &Backends,
+    client: Client,
+    msg: GroupMessage,
+    group: Group,
+) -> Result<()> {
     let Group { im, id } = group;
-    let sender = client
-        .get_group_member_info(msg.group_code, msg.from_uin)
-        .await?;
-    let sender_display = if sender.card_name.is_empty() {
-        sender.nickname
-    } else {
-        format!("{} ({})", sender.card_name, sender.nickname)
-    };
+    let sender_display = client.member_name(msg.group_code, msg.from_uin).await?;
     // TODO extract parse logic out of join
     match im {
         IM::QQ => {
@@ -63,9 +97,26 @@ async fn forward(client: Arc<ricq::Client>, msg: GroupMessage, group: Group) ->
                     _ => continue,
                 }
             }
+            let (_, sender) = accounts
+                .client_for(target_id)
+                .ok_or_else(|| anyhow!("no online account is a member of group {}", target_id))?;
             // TODO recall
-            client.send_group_message(target_id, new_msg).await?;
+            sender.send_group_message(target_id, new_msg).await?;
+            Ok(())
+        }
+        _ => {
+            let message = BridgedMessage {
+                source: Group::from_qq(msg.group_code),
+                sender_id: msg.from_uin.to_string(),
+                sender: sender_display,
+                avatar: Some(format!(
+                    "https://q1.qlogo.cn/g?b=qq&nk={}&s=640",
+                    msg.from_uin
+                )),
+                text: msg.elements.to_string(),
+            };
+            backends.get(&im)?.send(&id, &message).await?;
+            Ok(())
         }
-    };
-    Ok(())
+    }
 }
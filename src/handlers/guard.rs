@@ -1,24 +1,45 @@
+use std::sync::Arc;
+
 use dptree::case;
-use proc_qq::{GroupMessageEvent, MessageChainParseTrait, MessageSendToSourceTrait};
+use proc_qq::{GroupMessageEvent, MessageChainParseTrait, MessageContentTrait};
 use tracing::error;
 
+use crate::accounts::Accounts;
+use crate::client::{Client, Reply};
+use crate::config::Config;
 use crate::dp_helper::{EVHandler, UpdateKind};
 
 pub fn must_admin() -> EVHandler {
-    case![UpdateKind::GroupMessage].filter_async(|ev: GroupMessageEvent| async move {
-        let client = ev.client.clone();
-        let group = ev.inner.group_code;
-        let sender = ev.inner.from_uin;
-        match client.get_group_admin_list(group).await {
-            Ok(admins) => admins.contains_key(&sender),
-            Err(e) => {
-                error!(group, ?e, "failed to get admin list of group");
-                drop(
-                    ev.send_message_to_source("Failed to authenticate user.".parse_message_chain())
+    case![UpdateKind::GroupMessage].filter_async(
+        |client: Client, ev: GroupMessageEvent| async move {
+            let group = ev.inner.group_code;
+            let sender = ev.inner.from_uin;
+            match client.group_admins(group).await {
+                Ok(admins) => admins.contains(&sender),
+                Err(e) => {
+                    error!(group, ?e, "failed to get admin list of group");
+                    drop(
+                        ev.reply(
+                            &client,
+                            "Failed to authenticate user.".parse_message_chain(),
+                        )
                         .await,
-                );
-                false
+                    );
+                    false
+                }
             }
-        }
-    })
+        },
+    )
+}
+
+/// Only let through group messages that may be bridged: neither commands (anything starting with
+/// the command prefix, valid or not) nor messages sent by any of the bot's accounts.
+pub fn must_bridgeable() -> EVHandler {
+    case![UpdateKind::GroupMessage]
+        .filter(|config: Arc<Config>, ev: GroupMessageEvent| {
+            !ev.message_content()
+                .trim_start()
+                .starts_with(config.prefix.as_str())
+        })
+        .filter(|accounts: Accounts, ev: GroupMessageEvent| !accounts.is_own(ev.inner.from_uin))
 }
@@ -0,0 +1,27 @@
+use std::sync::Arc;
+
+use dptree::case;
+use proc_qq::{FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait};
+
+use crate::client::{Client, Reply};
+use crate::config::Config;
+use crate::dp_helper::{EVHandler, UpdateKind};
+use crate::handlers::parser::help_text;
+
+pub fn help_handler() -> EVHandler {
+    dptree::entry()
+        .branch(case![UpdateKind::FriendMessage].endpoint(
+            |kind: UpdateKind, config: Arc<Config>, client: Client, ev: FriendMessageEvent| async move {
+                ev.reply(&client, help_text(kind, &config.prefix).parse_message_chain())
+                    .await?;
+                Ok(())
+            },
+        ))
+        .branch(case![UpdateKind::GroupMessage].endpoint(
+            |kind: UpdateKind, config: Arc<Config>, client: Client, ev: GroupMessageEvent| async move {
+                ev.reply(&client, help_text(kind, &config.prefix).parse_message_chain())
+                    .await?;
+                Ok(())
+            },
+        ))
+}
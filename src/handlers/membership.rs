@@ -0,0 +1,74 @@
+use dptree::case;
+use proc_qq::{
+    GroupDisbandEvent, GroupLeaveEvent, GroupMessageEvent, GroupMessageRecallEvent, NewMemberEvent,
+};
+use tracing::info;
+
+use crate::accounts::Accounts;
+use crate::client::Client;
+use crate::dp_helper::{EVHandler, UpdateKind};
+
+/// Keep track of which account is a member of which group.
+pub fn membership_handler() -> EVHandler {
+    dptree::entry()
+        .branch(case![UpdateKind::NewMember].endpoint(
+            |accounts: Accounts, client: Client, ev: NewMemberEvent| async move {
+                let uin = client.uin().await;
+                if ev.inner.member_uin == uin {
+                    info!(uin, group = ev.inner.group_code, "account joined group");
+                    accounts.joined(uin, ev.inner.group_code);
+                }
+                Ok(())
+            },
+        ))
+        .branch(case![UpdateKind::GroupLeave].endpoint(
+            |accounts: Accounts, client: Client, ev: GroupLeaveEvent| async move {
+                let uin = client.uin().await;
+                if ev.inner.member_uin == uin {
+                    info!(uin, group = ev.inner.group_code, "account left group");
+                    accounts.left(uin, ev.inner.group_code);
+                }
+                Ok(())
+            },
+        ))
+        .branch(case![UpdateKind::GroupDisband].endpoint(
+            |accounts: Accounts, ev: GroupDisbandEvent| async move {
+                info!(group = ev.inner.group_code, "group disbanded");
+                accounts.forget_group(ev.inner.group_code);
+                Ok(())
+            },
+        ))
+}
+
+/// Swallow group events received by an account while another one is designated for the group, so
+/// that groups shared by several accounts are handled once. Events of groups no account is known
+/// to be designated for, e.g. before membership was learned, are let through.
+pub fn drop_duplicates() -> EVHandler {
+    dptree::entry()
+        .branch(
+            case![UpdateKind::GroupMessage]
+                .filter_async(
+                    |accounts: Accounts, client: Client, ev: GroupMessageEvent| async move {
+                        let uin = client.uin().await;
+                        // Whoever receives a group message is evidently a member.
+                        accounts.joined(uin, ev.inner.group_code);
+                        designated_elsewhere(&accounts, ev.inner.group_code, uin)
+                    },
+                )
+                .endpoint(|| async { Ok(()) }),
+        )
+        .branch(
+            case![UpdateKind::GroupMessageRecall]
+                .filter_async(
+                    |accounts: Accounts, client: Client, ev: GroupMessageRecallEvent| async move {
+                        let uin = client.uin().await;
+                        designated_elsewhere(&accounts, ev.inner.group_code, uin)
+                    },
+                )
+                .endpoint(|| async { Ok(()) }),
+        )
+}
+
+fn designated_elsewhere(accounts: &Accounts, group: i64, uin: i64) -> bool {
+    matches!(accounts.designated(group), Some(designated) if designated != uin)
+}
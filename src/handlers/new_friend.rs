@@ -2,18 +2,21 @@ use dptree::case;
 use proc_qq::NewFriendRequestEvent;
 use tracing::info;
 
+use crate::client::Client;
 use crate::dp_helper::{EVHandler, UpdateKind};
 use crate::handlers::auth::Token;
 
 pub fn new_friend_handler() -> EVHandler {
     case![UpdateKind::NewFriendRequest]
         .filter(|token: Token, ev: NewFriendRequestEvent| ev.inner.message.contains(&*token))
-        .endpoint(|ev: NewFriendRequestEvent| async move {
+        .endpoint(|client: Client, ev: NewFriendRequestEvent| async move {
             info!(
                 uid = ev.inner.req_uin,
                 nick = ev.inner.req_nick,
                 "Accepting new friend request"
             );
-            Ok(ev.accept().await?)
+            client
+                .solve_friend_request(ev.inner.msg_seq, ev.inner.req_uin, true)
+                .await
         })
 }
@@ -1,13 +1,20 @@
 use std::iter;
+use std::sync::Arc;
 
-use clap::{Parser, Subcommand};
+use clap::{ColorChoice, CommandFactory, Parser, Subcommand};
 use dptree::case;
-use proc_qq::{FriendMessageEvent, GroupMessageEvent, MessageContentTrait};
+use proc_qq::{FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait, MessageContentTrait};
 
+use crate::backup::Format;
+use crate::client::{Client, Reply};
+use crate::config::Config;
+use crate::db::IM;
 use crate::dp_helper::{EVHandler, UpdateKind};
 use crate::handlers::auth::Given;
+use crate::metrics;
 
 #[derive(Debug, Clone, Parser)]
+#[command(name = "im-bridge", color = ColorChoice::Never, disable_help_subcommand = true)]
 pub struct Args {
     #[command(subcommand)]
     pub cmd: Command,
@@ -15,52 +22,164 @@ pub struct Args {
 
 #[derive(Debug, Clone, Subcommand)]
 pub enum Command {
+    /// Manage clusters.
     Cluster {
         #[command(subcommand)]
         cmd: ClusterCommand,
+        /// Management token. Not needed for clusters you own or administrate.
         #[arg(short, long)]
-        token: Given,
+        token: Option<Given>,
     },
+    /// Request a one-time password for joining a group to a cluster.
     RequestOTP {
         #[arg(short, long)]
         token: Given,
     },
+    /// Join this group to a cluster. Without a one-time password, ask the cluster owner or admins
+    /// to approve.
     Join {
         cluster: String,
         #[arg(short, long)]
-        otp: Given,
+        otp: Option<Given>,
     },
+    /// Approve a pending join request.
+    Approve { id: u32 },
+    /// Deny a pending join request.
+    Deny { id: u32 },
+    /// List commands available here.
+    Help,
 }
 
 #[derive(Debug, Clone, Subcommand)]
 pub enum ClusterCommand {
+    /// Create a new cluster.
     Add,
+    /// List all clusters.
     List,
+    /// Show owner, admins and groups of a cluster.
+    Show { cluster: String },
+    /// Delete a cluster.
+    Delete { cluster: String },
+    /// Transfer ownership of a cluster.
+    Transfer { cluster: String, owner: i64 },
+    /// Delegate administration of a cluster to a user.
+    AddAdmin { cluster: String, uin: i64 },
+    /// Revoke administration of a cluster from a user.
+    RemoveAdmin { cluster: String, uin: i64 },
+    /// Remove a group from a cluster. Requires the token or ownership of the cluster.
+    Kick {
+        cluster: String,
+        im: IM,
+        id: String,
+        /// Post a notice to the kicked group.
+        #[arg(short, long)]
+        notify: bool,
+    },
+    /// Dump all clusters as a backup document.
+    Export {
+        #[arg(short, long, value_enum, default_value = "yaml")]
+        format: Format,
+    },
+    /// Restore clusters from a backup document. Quote it with single quotes.
+    Import { document: String },
+}
+
+/// Outcome of parsing a message that starts with the command prefix.
+#[derive(Debug, Clone)]
+enum Parsed {
+    Command(Command),
+    /// A known command with invalid arguments, or an explicit `--help`. Holds the text to reply.
+    Error(String),
+}
+
+/// Names of the commands that can be used in the given context.
+pub fn available_commands(kind: UpdateKind) -> &'static [&'static str] {
+    match kind {
+        UpdateKind::FriendMessage => &["cluster", "request-otp", "approve", "deny", "help"],
+        UpdateKind::GroupMessage => &["join", "help"],
+        _ => &[],
+    }
+}
+
+/// Render the help text listing commands available in the given context.
+pub fn help_text(kind: UpdateKind, prefix: &str) -> String {
+    let available = available_commands(kind);
+    let commands = Args::command();
+    let lines = commands
+        .get_subcommands()
+        .filter(|cmd| available.contains(&cmd.get_name()))
+        .map(|cmd| match cmd.get_about() {
+            Some(about) => format!("{}{} - {}", prefix, cmd.get_name(), about),
+            None => format!("{}{}", prefix, cmd.get_name()),
+        });
+    iter::once("Available commands:".to_string())
+        .chain(lines)
+        .chain(iter::once(format!(
+            "Use `{}<command> --help` for details.",
+            prefix
+        )))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse(input: &str) -> Option<Parsed> {
+    let split = shellwords::split(input).ok()?;
+    let name = split.first()?;
+    let known = Args::command()
+        .get_subcommands()
+        .any(|cmd| cmd.get_name() == name);
+    if !known {
+        return None;
+    }
+    metrics::COMMANDS.with_label_values(&[name.as_str()]).inc();
+    Some(
+        match Args::try_parse_from(iter::once("im-bridge".to_string()).chain(split)) {
+            Ok(args) => Parsed::Command(args.cmd),
+            Err(e) => Parsed::Error(e.to_string().trim_end().to_string()),
+        },
+    )
 }
 
 pub fn parse_cmd(ev: EVHandler) -> EVHandler {
     #[derive(Debug, Clone)]
     struct Input(String);
 
-    let parsed =
-        dptree::filter_map(|Input(input)| input.strip_prefix('/').map(|s| Input(s.to_string())))
-            .filter_map(|Input(input)| {
-                let split = shellwords::split(&input).ok()?;
-                Args::try_parse_from(iter::once("im-bridge".to_string()).chain(split))
-                    .map_err(|e| e.to_string())
-                    .ok()
-            })
-            .map(|args: Args| args.cmd)
-            .chain(ev);
+    let parsed = dptree::filter_map(|Input(input), config: Arc<Config>| {
+        input
+            .trim_start()
+            .strip_prefix(config.prefix.as_str())
+            .and_then(parse)
+    });
+    // Recognized commands are consumed here even if no handler accepts them, so that they never
+    // fall through to the forwarder.
+    let handled = case![Parsed::Command(cmd)].chain(
+        dptree::entry()
+            .branch(ev)
+            .branch(dptree::endpoint(|| async { Ok(()) })),
+    );
     dptree::entry()
         .branch(
             case![UpdateKind::FriendMessage]
                 .map(|ev: FriendMessageEvent| Input(ev.message_content()))
-                .chain(parsed.clone()),
+                .chain(parsed.clone())
+                .branch(case![Parsed::Error(msg)].endpoint(
+                    |msg: String, client: Client, ev: FriendMessageEvent| async move {
+                        ev.reply(&client, msg.parse_message_chain()).await?;
+                        Ok(())
+                    },
+                ))
+                .branch(handled.clone()),
         )
         .branch(
             case![UpdateKind::GroupMessage]
                 .map(|ev: GroupMessageEvent| Input(ev.message_content()))
-                .chain(parsed),
+                .chain(parsed)
+                .branch(case![Parsed::Error(msg)].endpoint(
+                    |msg: String, client: Client, ev: GroupMessageEvent| async move {
+                        ev.reply(&client, msg.parse_message_chain()).await?;
+                        Ok(())
+                    },
+                ))
+                .branch(handled),
         )
 }
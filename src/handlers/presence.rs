@@ -0,0 +1,50 @@
+use dptree::case;
+use tracing::{info, warn};
+
+use crate::accounts::Accounts;
+use crate::client::Client;
+use crate::dp_helper::{EVHandler, UpdateKind};
+use crate::status::{AccountIndex, BotStatus};
+
+pub fn presence_handler() -> EVHandler {
+    dptree::entry()
+        .branch(case![UpdateKind::ConnectedAndOnline].endpoint(
+            |status: BotStatus,
+             accounts: Accounts,
+             index: AccountIndex,
+             client: Client| async move {
+                let uin = client.uin().await;
+                info!(uin, "client online");
+                status.set_online(index, uin);
+                accounts.set_online(uin, client);
+                match accounts.refresh_groups(uin).await {
+                    Ok(groups) => info!(uin, groups, "group list loaded"),
+                    Err(e) => warn!(?e, uin, "failed to load group list"),
+                }
+                Ok(())
+            },
+        ))
+        .branch(
+            dptree::filter(|kind: UpdateKind| {
+                matches!(
+                    kind,
+                    UpdateKind::DisconnectedAndOffline
+                        | UpdateKind::KickedOffline
+                        | UpdateKind::MSFOffline
+                )
+            })
+            .endpoint(
+                |kind: UpdateKind,
+                 status: BotStatus,
+                 accounts: Accounts,
+                 index: AccountIndex| async move {
+                    let uin = status.set_offline(index);
+                    warn!(?kind, ?uin, "client offline");
+                    if let Some(uin) = uin {
+                        accounts.set_offline(uin);
+                    }
+                    Ok(())
+                },
+            ),
+        )
+}
@@ -0,0 +1,500 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use dptree::di::DependencyMap;
+use parking_lot::Mutex;
+use proc_qq::re_exports::ricq;
+use proc_qq::re_exports::ricq::device::Device;
+use proc_qq::re_exports::ricq::handler::DefaultHandler;
+use proc_qq::re_exports::ricq::msg::MessageChain;
+use proc_qq::re_exports::ricq::structs::{FriendMessage, GroupMessage, MessageReceipt};
+use proc_qq::re_exports::ricq::version::ANDROID_WATCH;
+use proc_qq::{FriendMessageEvent, GroupMessageEvent, MessageChainParseTrait};
+
+use crate::accounts::Accounts;
+use crate::bridge::Backends;
+use crate::client::{Client, QQClient};
+use crate::config::Config;
+use crate::db::{ClusterRole, ClusterStore, Group, MemoryStore, DB};
+use crate::dp_helper::{EVHandler, UpdateKind};
+use crate::handlers::approval::JoinRequests;
+use crate::handlers::auth::{Token, OTP};
+use crate::handlers::handler;
+use crate::status::{BotStatus, ForwardStats};
+
+/// Uin of the bot account.
+const BOT: i64 = 10000;
+/// Admin of every group.
+const ALICE: i64 = 1;
+/// A plain group member.
+const BOB: i64 = 2;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Sent {
+    Group(i64, String),
+    Friend(i64, String),
+}
+
+/// Records what would have been sent instead of talking to QQ.
+#[derive(Debug, Default)]
+struct FakeClient {
+    sent: Mutex<Vec<Sent>>,
+}
+
+#[async_trait]
+impl QQClient for FakeClient {
+    async fn uin(&self) -> i64 {
+        BOT
+    }
+    async fn send_group_message(
+        &self,
+        group: i64,
+        message: MessageChain,
+    ) -> Result<MessageReceipt> {
+        let mut sent = self.sent.lock();
+        sent.push(Sent::Group(group, message.to_string()));
+        Ok(MessageReceipt {
+            seqs: vec![sent.len() as i32],
+            rands: vec![0],
+            time: 0,
+        })
+    }
+    async fn send_friend_message(&self, uin: i64, message: MessageChain) -> Result<()> {
+        self.sent
+            .lock()
+            .push(Sent::Friend(uin, message.to_string()));
+        Ok(())
+    }
+    async fn group_admins(&self, _: i64) -> Result<Vec<i64>> {
+        Ok(vec![ALICE])
+    }
+    async fn member_name(&self, _: i64, uin: i64) -> Result<String> {
+        Ok(match uin {
+            ALICE => "Alice".to_string(),
+            BOB => "Bob".to_string(),
+            _ => bail!("not a member"),
+        })
+    }
+    async fn group_list(&self) -> Result<Vec<(i64, String)>> {
+        Ok(vec![])
+    }
+    async fn solve_friend_request(&self, _: i64, _: i64, _: bool) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The full handler tree wired to an in-memory store and a fake client.
+struct Harness {
+    handler: EVHandler,
+    deps: DependencyMap,
+    client: Arc<FakeClient>,
+    /// Events carry a ricq client, but handlers only use the fake one.
+    rq_client: Arc<ricq::Client>,
+    store: MemoryStore,
+    requests: JoinRequests,
+    token: Token,
+    otp: OTP,
+}
+
+impl Harness {
+    /// The bot is a member of the given groups.
+    fn new(groups: &[i64]) -> Self {
+        let client = Arc::new(FakeClient::default());
+        let accounts = Accounts::default();
+        accounts.set_online(BOT, client.clone());
+        for group in groups {
+            accounts.joined(BOT, *group);
+        }
+        let store = MemoryStore::default();
+        let db: DB = Arc::new(store.clone());
+        let token = Token::default();
+        let otp = OTP::new(db.clone());
+        let requests = JoinRequests::new(Duration::from_secs(60));
+        let deps = dptree::deps![
+            token,
+            otp.clone(),
+            requests.clone(),
+            db,
+            BotStatus::new(1),
+            accounts,
+            ForwardStats::default(),
+            Backends::default(),
+            Arc::new(Config::default())
+        ];
+        Self {
+            handler: handler(),
+            deps,
+            client,
+            rq_client: Arc::new(ricq::Client::new(
+                Device::random(),
+                &ANDROID_WATCH,
+                DefaultHandler,
+            )),
+            store,
+            requests,
+            token,
+            otp,
+        }
+    }
+    async fn dispatch<E: Send + Sync + 'static>(&self, kind: UpdateKind, event: E) {
+        let mut dmap = DependencyMap::new();
+        dmap.insert(kind);
+        dmap.insert::<Client>(self.client.clone());
+        dmap.insert(event);
+        dmap.insert_container(self.deps.clone());
+        if let ControlFlow::Break(result) = self.handler.dispatch(dmap).await {
+            result.unwrap();
+        }
+    }
+    async fn friend_message(&self, from: i64, text: &str) {
+        let event = FriendMessageEvent {
+            client: self.rq_client.clone(),
+            inner: FriendMessage {
+                seqs: vec![1],
+                rands: vec![1],
+                target: BOT,
+                time: 0,
+                from_uin: from,
+                from_nick: String::new(),
+                elements: text.parse_message_chain(),
+            },
+        };
+        self.dispatch(UpdateKind::FriendMessage, event).await;
+    }
+    async fn group_message(&self, group: i64, from: i64, text: &str) {
+        let event = GroupMessageEvent {
+            client: self.rq_client.clone(),
+            inner: GroupMessage {
+                seqs: vec![1],
+                rands: vec![1],
+                group_code: group,
+                group_name: format!("group {}", group),
+                group_card: String::new(),
+                time: 0,
+                from_uin: from,
+                elements: text.parse_message_chain(),
+            },
+        };
+        self.dispatch(UpdateKind::GroupMessage, event).await;
+    }
+    /// Take the messages sent so far.
+    fn sent(&self) -> Vec<Sent> {
+        std::mem::take(&mut *self.client.sent.lock())
+    }
+    /// Wait for messages sent from spawned tasks, like forwarded ones.
+    async fn sent_eventually(&self, count: usize) -> Vec<Sent> {
+        for _ in 0..100 {
+            if self.client.sent.lock().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.sent()
+    }
+}
+
+#[tokio::test]
+async fn help_lists_commands_of_context() {
+    let h = Harness::new(&[100]);
+    h.friend_message(ALICE, "/help").await;
+    let sent = h.sent();
+    let [Sent::Friend(ALICE, text)] = sent.as_slice() else {
+        panic!("unexpected replies: {:?}", sent);
+    };
+    assert!(text.contains("/request-otp"));
+    assert!(!text.contains("/join"));
+
+    h.group_message(100, BOB, "/help").await;
+    let sent = h.sent();
+    let [Sent::Group(100, text)] = sent.as_slice() else {
+        panic!("unexpected replies: {:?}", sent);
+    };
+    assert!(text.contains("/join"));
+    assert!(!text.contains("/request-otp"));
+}
+
+#[tokio::test]
+async fn request_otp_checks_token() {
+    let h = Harness::new(&[]);
+    h.friend_message(ALICE, "/request-otp --token wrong").await;
+    assert_eq!(h.sent(), [Sent::Friend(ALICE, "Invalid token".to_string())]);
+
+    h.friend_message(ALICE, &format!("/request-otp --token {}", h.token))
+        .await;
+    let sent = h.sent();
+    let [Sent::Friend(ALICE, text)] = sent.as_slice() else {
+        panic!("unexpected replies: {:?}", sent);
+    };
+    let otp = text
+        .strip_prefix("Your one-time password is:\n")
+        .expect("reply carries the password");
+    assert!(h.otp.verify(otp).await.unwrap());
+}
+
+#[tokio::test]
+async fn cluster_add_makes_sender_owner() {
+    let h = Harness::new(&[]);
+    h.friend_message(ALICE, &format!("/cluster --token {} add", h.token))
+        .await;
+    assert_eq!(h.sent().len(), 1);
+    let clusters = h.store.all_clusters().await.unwrap();
+    assert_eq!(clusters.len(), 1);
+    assert_eq!(clusters[0].owner, Some(ALICE));
+}
+
+#[tokio::test]
+async fn group_admin_joins_with_otp() {
+    let h = Harness::new(&[100]);
+    let cluster = h.store.new_cluster(None).await.unwrap();
+    let otp = h.otp.generate_new().await.unwrap();
+    h.group_message(100, ALICE, &format!("/join {} --otp {}", cluster, otp))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Group(100, "Joined to cluster".to_string())]
+    );
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert!(found.groups.contains(&Group::from_qq(100)));
+}
+
+#[tokio::test]
+async fn join_requires_group_admin() {
+    let h = Harness::new(&[100]);
+    let cluster = h.store.new_cluster(None).await.unwrap();
+    let otp = h.otp.generate_new().await.unwrap();
+    h.group_message(100, BOB, &format!("/join {} --otp {}", cluster, otp))
+        .await;
+    assert!(h.sent().is_empty());
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert!(found.groups.is_empty());
+}
+
+#[tokio::test]
+async fn wrong_otp_files_no_join_request() {
+    let h = Harness::new(&[100]);
+    let cluster = h.store.new_cluster(Some(ALICE)).await.unwrap();
+    h.group_message(100, ALICE, &format!("/join {} --otp wrong", cluster))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Group(100, "Invalid one-time password".to_string())]
+    );
+    // Request ids start at 1.
+    assert!(h.requests.get(1).is_none());
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert!(found.groups.is_empty());
+}
+
+#[tokio::test]
+async fn forwards_to_other_groups_of_cluster() {
+    let h = Harness::new(&[100, 200, 300]);
+    let cluster = h.store.new_cluster(None).await.unwrap();
+    h.store.join(&cluster, &Group::from_qq(100)).await.unwrap();
+    h.store.join(&cluster, &Group::from_qq(200)).await.unwrap();
+
+    h.group_message(100, BOB, "hello").await;
+    assert_eq!(
+        h.sent_eventually(1).await,
+        [Sent::Group(200, "Bob: hello".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn commands_and_own_messages_are_not_forwarded() {
+    let h = Harness::new(&[100, 200]);
+    let cluster = h.store.new_cluster(None).await.unwrap();
+    h.store.join(&cluster, &Group::from_qq(100)).await.unwrap();
+    h.store.join(&cluster, &Group::from_qq(200)).await.unwrap();
+
+    h.group_message(100, BOB, "/not-a-command").await;
+    h.group_message(100, BOT, "Bob: hello").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(h.sent().is_empty());
+}
+
+#[tokio::test]
+async fn owner_approves_join_request() {
+    let h = Harness::new(&[100]);
+    let cluster = h.store.new_cluster(Some(ALICE)).await.unwrap();
+    let id = h
+        .requests
+        .insert(cluster.clone(), 100, "group 100".to_string(), BOB);
+    h.friend_message(ALICE, &format!("/approve {}", id)).await;
+    assert_eq!(
+        h.sent(),
+        [
+            Sent::Group(100, "Join request approved. Joined to cluster".to_string()),
+            Sent::Friend(
+                ALICE,
+                format!("Group group 100 joined cluster {}.", cluster)
+            ),
+        ]
+    );
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert!(found.groups.contains(&Group::from_qq(100)));
+    assert!(h.requests.get(id).is_none());
+}
+
+#[tokio::test]
+async fn admin_denies_join_request() {
+    let h = Harness::new(&[100]);
+    let cluster = h.store.new_cluster(Some(BOB)).await.unwrap();
+    h.store.add_admin(&cluster, ALICE).await.unwrap();
+    let id = h
+        .requests
+        .insert(cluster.clone(), 100, "group 100".to_string(), BOB);
+    h.friend_message(ALICE, &format!("/deny {}", id)).await;
+    assert_eq!(
+        h.sent(),
+        [
+            Sent::Group(
+                100,
+                format!("Request to join cluster {} was denied.", cluster)
+            ),
+            Sent::Friend(ALICE, "Join request denied.".to_string()),
+        ]
+    );
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert!(found.groups.is_empty());
+    assert!(h.requests.get(id).is_none());
+}
+
+#[tokio::test]
+async fn outsiders_cannot_decide_join_requests() {
+    let h = Harness::new(&[100]);
+    let cluster = h.store.new_cluster(Some(ALICE)).await.unwrap();
+    let id = h
+        .requests
+        .insert(cluster.clone(), 100, "group 100".to_string(), BOB);
+    for command in ["approve", "deny"] {
+        h.friend_message(BOB, &format!("/{} {}", command, id)).await;
+        assert_eq!(
+            h.sent(),
+            [Sent::Friend(
+                BOB,
+                "Only the cluster owner and admins can decide on this request.".to_string()
+            )]
+        );
+    }
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert!(found.groups.is_empty());
+    assert!(h.requests.get(id).is_some());
+}
+
+#[tokio::test]
+async fn owner_manages_admins_and_transfers() {
+    let h = Harness::new(&[]);
+    let cluster = h.store.new_cluster(Some(ALICE)).await.unwrap();
+
+    h.friend_message(ALICE, &format!("/cluster add-admin {} {}", cluster, BOB))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Friend(
+            ALICE,
+            format!("{} is now an admin of the cluster.", BOB)
+        )]
+    );
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert_eq!(found.role_of(BOB), Some(ClusterRole::Admin));
+
+    h.friend_message(ALICE, &format!("/cluster remove-admin {} {}", cluster, BOB))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Friend(
+            ALICE,
+            format!("{} is no longer an admin of the cluster.", BOB)
+        )]
+    );
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert_eq!(found.role_of(BOB), None);
+
+    h.friend_message(ALICE, &format!("/cluster transfer {} {}", cluster, BOB))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Friend(
+            ALICE,
+            format!("Cluster is now owned by {}.", BOB)
+        )]
+    );
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert_eq!(found.owner, Some(BOB));
+    assert_eq!(found.role_of(ALICE), None);
+}
+
+#[tokio::test]
+async fn admins_cannot_take_owner_actions() {
+    let h = Harness::new(&[]);
+    let cluster = h.store.new_cluster(Some(ALICE)).await.unwrap();
+    h.store.add_admin(&cluster, BOB).await.unwrap();
+
+    for command in [
+        format!("transfer {} {}", cluster, BOB),
+        format!("add-admin {} {}", cluster, BOT),
+        format!("remove-admin {} {}", cluster, BOB),
+        format!("delete {}", cluster),
+    ] {
+        h.friend_message(BOB, &format!("/cluster {}", command))
+            .await;
+        assert_eq!(
+            h.sent(),
+            [Sent::Friend(BOB, "Permission denied.".to_string())],
+            "{}",
+            command
+        );
+    }
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert_eq!(found.owner, Some(ALICE));
+    assert_eq!(found.admins.into_iter().collect::<Vec<_>>(), [BOB]);
+
+    // Showing the cluster only takes an admin.
+    h.friend_message(BOB, &format!("/cluster show {}", cluster))
+        .await;
+    let sent = h.sent();
+    let [Sent::Friend(BOB, text)] = sent.as_slice() else {
+        panic!("unexpected replies: {:?}", sent);
+    };
+    assert!(text.starts_with(&format!("Cluster: {}", cluster)));
+}
+
+#[tokio::test]
+async fn only_owner_kicks_groups() {
+    let h = Harness::new(&[100, 200]);
+    let cluster = h.store.new_cluster(Some(ALICE)).await.unwrap();
+    h.store.add_admin(&cluster, BOB).await.unwrap();
+    h.store.join(&cluster, &Group::from_qq(100)).await.unwrap();
+
+    h.friend_message(BOB, &format!("/cluster kick {} qq 100", cluster))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Friend(BOB, "Permission denied.".to_string())]
+    );
+
+    h.friend_message(ALICE, &format!("/cluster kick {} qq 200", cluster))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Friend(
+            ALICE,
+            "That group is not in the cluster.".to_string()
+        )]
+    );
+
+    h.friend_message(ALICE, &format!("/cluster kick {} qq 100", cluster))
+        .await;
+    assert_eq!(
+        h.sent(),
+        [Sent::Friend(
+            ALICE,
+            "Group kicked from cluster.".to_string()
+        )]
+    );
+    let found = h.store.cluster(&cluster).await.unwrap().unwrap();
+    assert!(found.groups.is_empty());
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::json;
+use tracing::info;
+
+use crate::accounts::Accounts;
+use crate::config::HttpConfig;
+use crate::db::DB;
+use crate::handlers::auth::{secret_eq, OTP};
+use crate::metrics;
+use crate::status::{BotStatus, ForwardStats};
+
+mod api;
+mod dashboard;
+mod health;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub db: DB,
+    pub otp: OTP,
+    pub status: BotStatus,
+    pub accounts: Accounts,
+    pub stats: ForwardStats,
+    /// Bearer token required by the admin API.
+    pub token: String,
+}
+
+/// Error response carrying a status code and a message rendered as `{"error": ...}`.
+#[derive(Debug)]
+pub struct ApiError(StatusCode, String);
+
+impl ApiError {
+    pub fn not_found(msg: impl Into<String>) -> Self {
+        Self(StatusCode::NOT_FOUND, msg.into())
+    }
+    pub fn bad_request(msg: impl Into<String>) -> Self {
+        Self(StatusCode::BAD_REQUEST, msg.into())
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+async fn bearer_auth<B>(State(state): State<AppState>, req: Request<B>, next: Next<B>) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |given| secret_eq(given, &state.token));
+    if authorized {
+        next.run(req).await
+    } else {
+        ApiError(StatusCode::UNAUTHORIZED, "Invalid token".to_string()).into_response()
+    }
+}
+
+/// HTTP basic auth for the dashboard, so that browsers prompt for credentials. Any user name is
+/// accepted, the password is the API token. Form posts from other origins are rejected.
+async fn basic_auth<B>(State(state): State<AppState>, req: Request<B>, next: Next<B>) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|credentials| {
+            credentials
+                .split_once(':')
+                .map(|(_, password)| secret_eq(password, &state.token))
+        })
+        .unwrap_or(false);
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"im-bridge\"")],
+        )
+            .into_response();
+    }
+    if req.method() == Method::POST {
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|v| v.to_str().ok());
+        let origin = req
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .map(|origin| origin.split_once("://").map_or(origin, |(_, rest)| rest));
+        if matches!((origin, host), (Some(origin), Some(host)) if origin != host) {
+            return ApiError(StatusCode::FORBIDDEN, "Cross-origin request".to_string())
+                .into_response();
+        }
+    }
+    next.run(req).await
+}
+
+pub async fn serve(config: HttpConfig, state: AppState) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(|| async { metrics::render() }))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .nest(
+            "/api",
+            api::router().route_layer(middleware::from_fn_with_state(state.clone(), bearer_auth)),
+        )
+        .nest(
+            "/admin",
+            dashboard::router()
+                .route_layer(middleware::from_fn_with_state(state.clone(), basic_auth)),
+        )
+        .with_state(state);
+    info!(bind = %config.bind, "HTTP server listening");
+    axum::Server::bind(&config.bind)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
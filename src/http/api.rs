@@ -0,0 +1,123 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::db::{Cluster, Group, IM};
+use crate::http::{ApiError, AppState};
+use crate::status::StatusSnapshot;
+
+type ApiResult<T> = Result<T, ApiError>;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/status", get(status))
+        .route("/clusters", get(list_clusters).post(create_cluster))
+        .route("/clusters/:name", get(show_cluster).delete(delete_cluster))
+        .route(
+            "/clusters/:name/groups/:im/:id",
+            put(join_group).delete(leave_group),
+        )
+        .route("/otp", post(issue_otp))
+}
+
+async fn status(State(state): State<AppState>) -> Json<StatusSnapshot> {
+    Json(state.status.snapshot())
+}
+
+async fn list_clusters(State(state): State<AppState>) -> ApiResult<Json<Vec<String>>> {
+    Ok(Json(state.db.clusters().await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateCluster {
+    owner: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Created {
+    name: String,
+}
+
+async fn create_cluster(
+    State(state): State<AppState>,
+    body: Option<Json<CreateCluster>>,
+) -> ApiResult<(StatusCode, Json<Created>)> {
+    let owner = body.and_then(|Json(body)| body.owner);
+    let name = state.db.new_cluster(owner).await?;
+    info!(name, "new cluster created via API");
+    Ok((StatusCode::CREATED, Json(Created { name })))
+}
+
+async fn existing(state: &AppState, name: &str) -> ApiResult<Cluster> {
+    state
+        .db
+        .cluster(name)
+        .await?
+        .ok_or_else(|| ApiError::not_found("No such cluster"))
+}
+
+async fn show_cluster(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<Cluster>> {
+    Ok(Json(existing(&state, &name).await?))
+}
+
+async fn delete_cluster(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    existing(&state, &name).await?;
+    state.db.delete_cluster(&name).await?;
+    info!(name, "cluster deleted via API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn group(im: &str, id: String) -> ApiResult<Group> {
+    let im: IM = im
+        .parse()
+        .map_err(|e: anyhow::Error| ApiError::bad_request(e.to_string()))?;
+    Ok(Group { im, id })
+}
+
+async fn join_group(
+    State(state): State<AppState>,
+    Path((name, im, id)): Path<(String, String, String)>,
+) -> ApiResult<StatusCode> {
+    let group = group(&im, id)?;
+    let cluster = existing(&state, &name).await?;
+    if cluster.groups.contains(&group) {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    state.db.join(&name, &group).await?;
+    info!(?group, cluster = name, "group joined cluster via API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn leave_group(
+    State(state): State<AppState>,
+    Path((name, im, id)): Path<(String, String, String)>,
+) -> ApiResult<StatusCode> {
+    let group = group(&im, id)?;
+    let cluster = existing(&state, &name).await?;
+    if !cluster.groups.contains(&group) {
+        return Err(ApiError::not_found("Group is not in the cluster"));
+    }
+    state.db.leave(&name, &group).await?;
+    info!(?group, cluster = name, "group left cluster via API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+struct IssuedOTP {
+    otp: String,
+}
+
+async fn issue_otp(State(state): State<AppState>) -> ApiResult<Json<IssuedOTP>> {
+    Ok(Json(IssuedOTP {
+        otp: state.otp.generate_new().await?,
+    }))
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use axum::extract::{Path, State};
+use axum::response::{Html, Redirect};
+use axum::routing::{get, post};
+use axum::{Form, Router};
+use itertools::Itertools;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::db::{Cluster, Group, IM};
+use crate::http::{ApiError, AppState};
+
+type PageResult<T> = Result<T, ApiError>;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(index))
+        .route("/clusters", post(create_cluster))
+        .route("/clusters/:name/rename", post(rename_cluster))
+        .route("/clusters/:name/delete", post(delete_cluster))
+        .route("/clusters/:name/kick", post(kick_group))
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em auto;max-width:60em;color:#222}\
+table{border-collapse:collapse;width:100%;margin:.5em 0}\
+td,th{border:1px solid #ccc;padding:.3em .6em;text-align:left}\
+section{border:1px solid #ddd;border-radius:4px;padding:0 1em 1em;margin:1em 0}\
+form{display:inline-block;margin:.2em .5em .2em 0}.err{color:#b00}";
+
+fn escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Percent-encode a path segment, leaving only unreserved characters as they are.
+fn encode_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Names of the QQ groups the online accounts are in, keyed by group code.
+async fn qq_group_names(state: &AppState) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for client in state.accounts.clients() {
+        match client.group_list().await {
+            Ok(groups) => names.extend(
+                groups
+                    .into_iter()
+                    .map(|(code, name)| (code.to_string(), name)),
+            ),
+            Err(e) => warn!(?e, "failed to get group list"),
+        }
+    }
+    names
+}
+
+fn render_cluster(out: &mut String, cluster: &Cluster, names: &HashMap<String, String>) {
+    let name = escape(&cluster.name);
+    // Percent-encoded, the name contains nothing HTML would have to escape.
+    let path = format!("/admin/clusters/{}", encode_segment(&cluster.name));
+    let owner = cluster
+        .owner
+        .map_or_else(|| "(none)".to_string(), |o| o.to_string());
+    let admins = cluster.admins.iter().join(", ");
+    let _ = write!(
+        out,
+        "<section><h3>{name}</h3><p>Owner: {owner}<br>Admins: {admins}</p>\
+        <table><tr><th>IM</th><th>ID</th><th>Name</th><th></th></tr>"
+    );
+    for group in cluster.groups.iter().sorted_by(|a, b| a.id.cmp(&b.id)) {
+        let group_name = match group.im {
+            IM::QQ => names.get(&group.id).map_or("", String::as_str),
+            IM::Discord | IM::Matrix | IM::Irc => "",
+        };
+        let _ = write!(
+            out,
+            "<tr><td>{im:?}</td><td>{id}</td><td>{group_name}</td><td>\
+            <form method=post action=\"{path}/kick\">\
+            <input type=hidden name=im value=\"{im:?}\"><input type=hidden name=id value=\"{id}\">\
+            <button>Kick</button></form></td></tr>",
+            im = group.im,
+            path = path,
+            id = escape(&group.id),
+            group_name = escape(group_name),
+        );
+    }
+    let _ = write!(
+        out,
+        "</table>\
+        <form method=post action=\"{path}/rename\">\
+        <input name=new_name placeholder=\"New name\" required><button>Rename</button></form>\
+        <form method=post action=\"{path}/delete\">\
+        <label><input type=checkbox required> Really delete</label> <button>Delete</button></form>\
+        </section>"
+    );
+}
+
+async fn index(State(state): State<AppState>) -> PageResult<Html<String>> {
+    let clusters = state.db.all_clusters().await?;
+    let names = qq_group_names(&state).await;
+    let status = state.status.snapshot();
+
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "<!doctype html><html><head><meta charset=utf-8><title>IM Bridge</title>\
+        <style>{STYLE}</style></head><body><h1>IM Bridge</h1>"
+    );
+    out.push_str("<h2>Status</h2><p>");
+    for (index, account) in status.accounts.iter().enumerate() {
+        let _ = write!(
+            out,
+            "QQ account {}: {} {}<br>",
+            index + 1,
+            if account.online { "online" } else { "offline" },
+            account
+                .uin
+                .map(|uin| format!("({})", uin))
+                .unwrap_or_default(),
+        );
+    }
+    let _ = write!(
+        out,
+        "Delivered: {} &middot; Failed: {}</p>",
+        state.stats.delivered(),
+        state.stats.failed(),
+    );
+
+    out.push_str("<h2>Recent forward failures</h2>");
+    let failures = state.stats.recent_failures();
+    if failures.is_empty() {
+        out.push_str("<p>None.</p>");
+    } else {
+        out.push_str("<table><tr><th>When</th><th>From</th><th>To</th><th>Error</th></tr>");
+        for failure in failures {
+            let _ = write!(
+                out,
+                "<tr><td>{}s ago</td><td>{:?} {}</td><td>{:?} {}</td><td class=err>{}</td></tr>",
+                failure.at.elapsed().as_secs(),
+                failure.source.im,
+                escape(&failure.source.id),
+                failure.target.im,
+                escape(&failure.target.id),
+                escape(&failure.error),
+            );
+        }
+        out.push_str("</table>");
+    }
+
+    out.push_str(
+        "<h2>Clusters</h2><form method=post action=\"/admin/clusters\">\
+        <input name=owner placeholder=\"Owner QQ (optional)\"><button>Create cluster</button></form>",
+    );
+    for cluster in clusters.iter().sorted_by(|a, b| a.name.cmp(&b.name)) {
+        render_cluster(&mut out, cluster, &names);
+    }
+    out.push_str("</body></html>");
+    Ok(Html(out))
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateForm {
+    #[serde(default)]
+    owner: String,
+}
+
+async fn create_cluster(
+    State(state): State<AppState>,
+    Form(form): Form<CreateForm>,
+) -> PageResult<Redirect> {
+    let owner = match form.owner.trim() {
+        "" => None,
+        owner => Some(
+            owner
+                .parse()
+                .map_err(|_| ApiError::bad_request("Owner must be a QQ number"))?,
+        ),
+    };
+    let name = state.db.new_cluster(owner).await?;
+    info!(name, "new cluster created via dashboard");
+    Ok(Redirect::to("/admin"))
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameForm {
+    new_name: String,
+}
+
+async fn rename_cluster(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Form(form): Form<RenameForm>,
+) -> PageResult<Redirect> {
+    let new_name = form.new_name.trim();
+    if !Cluster::is_valid_name(new_name) {
+        return Err(ApiError::bad_request(
+            "Name may only contain letters, digits, '-' and '_'",
+        ));
+    }
+    state.db.rename_cluster(&name, new_name).await?;
+    info!(name, new_name, "cluster renamed via dashboard");
+    Ok(Redirect::to("/admin"))
+}
+
+async fn delete_cluster(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> PageResult<Redirect> {
+    state.db.delete_cluster(&name).await?;
+    info!(name, "cluster deleted via dashboard");
+    Ok(Redirect::to("/admin"))
+}
+
+#[derive(Debug, Deserialize)]
+struct KickForm {
+    im: String,
+    id: String,
+}
+
+async fn kick_group(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Form(form): Form<KickForm>,
+) -> PageResult<Redirect> {
+    let im: IM = form
+        .im
+        .parse()
+        .map_err(|e: anyhow::Error| ApiError::bad_request(e.to_string()))?;
+    let group = Group { im, id: form.id };
+    state.db.leave(&name, &group).await?;
+    info!(?group, cluster = name, "group kicked via dashboard");
+    Ok(Redirect::to("/admin"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::render_cluster;
+    use crate::db::Cluster;
+
+    #[test]
+    fn cluster_names_are_escaped_and_encoded() {
+        let cluster = Cluster {
+            name: "x');alert(1)// a/b?".to_string(),
+            groups: HashSet::new(),
+            owner: None,
+            admins: HashSet::new(),
+        };
+        let mut out = String::new();
+        render_cluster(&mut out, &cluster, &HashMap::new());
+        assert!(out.contains("<h3>x&#39;);alert(1)// a/b?</h3>"));
+        assert!(out
+            .contains("action=\"/admin/clusters/x%27%29%3Balert%281%29%2F%2F%20a%2Fb%3F/delete\""));
+        assert!(!out.contains("onsubmit"));
+    }
+}
@@ -0,0 +1,47 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::http::AppState;
+use crate::status::StatusSnapshot;
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    #[serde(flatten)]
+    qq: StatusSnapshot,
+    /// The cluster storage is reachable.
+    storage: bool,
+}
+
+async fn check(state: &AppState) -> Health {
+    let storage = match state.db.ping().await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(?e, "storage ping failed");
+            false
+        }
+    };
+    Health {
+        qq: state.status.snapshot(),
+        storage,
+    }
+}
+
+/// Liveness: the process is serving requests. Always succeeds, reporting the QQ state only, so
+/// that a slow storage does not get the process restarted. Storage is left to [`readyz`].
+pub async fn healthz(State(state): State<AppState>) -> Json<StatusSnapshot> {
+    Json(state.status.snapshot())
+}
+
+/// Readiness: every QQ account is logged in and the storage is reachable.
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Health>) {
+    let health = check(&state).await;
+    let code = if health.qq.online && health.storage {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(health))
+}
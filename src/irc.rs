@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::bridge::{Backend, BridgedMessage, Relay};
+use crate::config::IrcConfig;
+
+mod connection;
+
+/// Longest line servers accept, including the trailing CRLF.
+const MAX_LINE: usize = 512;
+/// Room left for the `:nick!user@host ` prefix the server adds when passing a message on.
+const PREFIX_RESERVE: usize = 100;
+/// Longest sender shown in front of bridged text, in bytes.
+const MAX_SENDER: usize = 64;
+/// Least text sent per line, even if an unusually long channel name leaves less room.
+const MIN_BUDGET: usize = 64;
+/// Lines queued for a network before sends fail, e.g. while it reconnects.
+const QUEUE: usize = 256;
+
+/// A message from or to a server, without tags.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Line {
+    /// Sender, like `nick!user@host`.
+    prefix: Option<String>,
+    command: String,
+    params: Vec<String>,
+}
+
+impl Line {
+    fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        if rest.starts_with('@') {
+            rest = rest.split_once(' ')?.1;
+        }
+        let prefix = match rest.strip_prefix(':') {
+            Some(prefixed) => {
+                let (prefix, after) = prefixed.split_once(' ')?;
+                rest = after;
+                Some(prefix.to_string())
+            }
+            None => None,
+        };
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?.to_ascii_uppercase();
+        let params = words
+            .map(ToString::to_string)
+            .chain(trailing.map(ToString::to_string))
+            .collect();
+        Some(Self {
+            prefix,
+            command,
+            params,
+        })
+    }
+    /// Encode for sending, always passing the last parameter as trailing.
+    fn encode(&self) -> String {
+        let mut line = self.command.clone();
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                line.push(' ');
+                line.push_str(param);
+            }
+            line.push_str(" :");
+            // Line breaks would end the line early and send the rest as a command.
+            line.extend(
+                last.chars()
+                    .map(|c| if c == '\r' || c == '\n' { ' ' } else { c }),
+            );
+        }
+        line.push_str("\r\n");
+        line
+    }
+    /// Nick of the sender.
+    fn nick(&self) -> Option<&str> {
+        let prefix = self.prefix.as_deref()?;
+        Some(prefix.split_once('!').map_or(prefix, |(nick, _)| nick))
+    }
+}
+
+/// Split a bridged message into `PRIVMSG` texts that fit in a line, each starting with the
+/// sender's nick so that continuation lines can be attributed.
+fn split_message(channel: &str, sender: &str, text: &str) -> Vec<String> {
+    let head = format!("<{}> ", truncate(sender, MAX_SENDER));
+    let budget = (MAX_LINE - PREFIX_RESERVE - "PRIVMSG  :\r\n".len())
+        .saturating_sub(channel.len() + head.len())
+        .max(MIN_BUDGET);
+    let mut texts = Vec::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let mut rest = line;
+        while !rest.is_empty() {
+            // The budget is at least MIN_BUDGET, more than any char takes, so this never backs up
+            // to an empty piece.
+            let mut end = rest.len().min(budget);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            // Break at a space if there is one, so that words stay whole.
+            if end < rest.len() {
+                if let Some(space) = rest[..end].rfind(' ').filter(|space| *space > 0) {
+                    end = space;
+                }
+            }
+            texts.push(format!("{}{}", head, &rest[..end]));
+            rest = rest[end..].trim_start();
+        }
+    }
+    texts
+}
+
+/// The longest prefix of the text that is at most `max` bytes long and ends at a char boundary.
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Connections to the configured IRC networks, by name.
+pub struct Irc {
+    networks: HashMap<String, mpsc::Sender<Line>>,
+}
+
+/// Connections to be started once the relay they feed exists, which in turn needs the [`Irc`]
+/// backend.
+pub struct Connections(Vec<(IrcConfig, mpsc::Receiver<Line>)>);
+
+impl Connections {
+    /// Connect to every network in the background, relaying messages of their channels.
+    pub fn spawn(self, relay: Relay) {
+        for (config, queue) in self.0 {
+            tokio::spawn(connection::run(config, queue, relay.clone()));
+        }
+    }
+}
+
+impl Irc {
+    pub fn new(configs: &[IrcConfig]) -> (Self, Connections) {
+        let mut networks = HashMap::new();
+        let mut connections = Vec::new();
+        for config in configs {
+            let (tx, rx) = mpsc::channel(QUEUE);
+            networks.insert(config.name.clone(), tx);
+            connections.push((config.clone(), rx));
+        }
+        (Self { networks }, Connections(connections))
+    }
+    fn privmsg(&self, group: &str, texts: impl FnOnce(&str) -> Vec<String>) -> Result<()> {
+        let (network, channel) = group
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid IRC group {}", group))?;
+        let queue = self
+            .networks
+            .get(network)
+            .ok_or_else(|| anyhow!("no IRC network named {}", network))?;
+        for text in texts(channel) {
+            queue
+                .try_send(Line {
+                    prefix: None,
+                    command: "PRIVMSG".to_string(),
+                    params: vec![channel.to_string(), text],
+                })
+                .map_err(|e| anyhow!("IRC network {} is not keeping up: {}", network, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Backend for Irc {
+    async fn send(&self, group: &str, message: &BridgedMessage) -> Result<()> {
+        self.privmsg(group, |channel| {
+            split_message(channel, &message.sender, &message.text)
+        })
+    }
+    async fn send_notice(&self, group: &str, text: &str) -> Result<()> {
+        self.privmsg(group, |channel| split_message(channel, "im-bridge", text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_message, Line, MAX_LINE, PREFIX_RESERVE};
+
+    #[test]
+    fn lines_are_parsed() {
+        let line = Line::parse("@time=x :alice!a@host PRIVMSG #chan :hello there\r\n").unwrap();
+        assert_eq!(line.nick(), Some("alice"));
+        assert_eq!(line.command, "PRIVMSG");
+        assert_eq!(line.params, ["#chan", "hello there"]);
+
+        let ping = Line::parse("PING :server").unwrap();
+        assert_eq!(ping.prefix, None);
+        assert_eq!(ping.params, ["server"]);
+        assert_eq!(ping.encode(), "PING :server\r\n");
+    }
+
+    #[test]
+    fn long_messages_are_split_at_spaces() {
+        let text = vec!["word"; 200].join(" ");
+        let texts = split_message("#chan", "Alice", &text);
+        assert!(texts.len() > 1);
+        for text in &texts {
+            assert!(text.starts_with("<Alice> word"));
+            assert!(text.ends_with("word"));
+            assert!(text.len() + "PRIVMSG #chan :\r\n".len() <= MAX_LINE - PREFIX_RESERVE);
+        }
+        let words: usize = texts.iter().map(|text| text.matches("word").count()).sum();
+        assert_eq!(words, 200);
+    }
+
+    #[test]
+    fn multibyte_text_is_split_at_char_boundaries() {
+        let texts = split_message("#chan", "Alice", &"字".repeat(300));
+        assert!(texts.len() > 1);
+        let chars: usize = texts
+            .iter()
+            .map(|text| text.trim_start_matches("<Alice> ").chars().count())
+            .sum();
+        assert_eq!(chars, 300);
+    }
+
+    #[test]
+    fn long_senders_are_truncated() {
+        let sender = "字".repeat(400);
+        let texts = split_message("#chan", &sender, "hello");
+        assert_eq!(texts.len(), 1);
+        assert!(texts[0].ends_with("> hello"));
+        assert!(texts[0].len() + "PRIVMSG #chan :\r\n".len() <= MAX_LINE - PREFIX_RESERVE);
+    }
+
+    #[test]
+    fn long_channels_still_make_progress() {
+        let channel = format!("#{}", "c".repeat(500));
+        let texts = split_message(&channel, "Alice", &"字".repeat(100));
+        let chars: usize = texts
+            .iter()
+            .map(|text| text.trim_start_matches("<Alice> ").chars().count())
+            .sum();
+        assert_eq!(chars, 100);
+    }
+}
@@ -0,0 +1,210 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_native_tls::native_tls;
+use tracing::{debug, error, info, warn};
+
+use crate::bridge::{BridgedMessage, Relay};
+use crate::config::IrcConfig;
+use crate::db::{Group, IM};
+use crate::irc::Line;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// A connection that lasted this long resets the backoff.
+const STABLE_CONNECTION: Duration = Duration::from_secs(10 * 60);
+/// Pause after each message sent, so that servers do not disconnect us for flooding.
+const SEND_DELAY: Duration = Duration::from_millis(500);
+/// Give up on a connection the server stopped answering pings on.
+const READ_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Keep connected to a network, reconnecting with exponential backoff whenever the connection
+/// drops. Lines queued while disconnected are sent after reconnecting, and so is a line the
+/// connection dropped while sending.
+pub(super) async fn run(config: IrcConfig, mut queue: mpsc::Receiver<Line>, relay: Relay) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut pending = None;
+    loop {
+        let started = Instant::now();
+        match session(&config, &mut queue, &mut pending, &relay).await {
+            Ok(()) => warn!(network = config.name, "IRC connection closed"),
+            Err(e) => error!(?e, network = config.name, "IRC connection failed"),
+        }
+        if started.elapsed() >= STABLE_CONNECTION {
+            backoff = INITIAL_BACKOFF;
+        }
+        info!(?backoff, network = config.name, "reconnecting to IRC");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn connect(config: &IrcConfig) -> Result<Box<dyn Stream>> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    Ok(if config.tls {
+        let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+        Box::new(connector.connect(&config.host, tcp).await?)
+    } else {
+        Box::new(tcp)
+    })
+}
+
+fn command(command: &str, params: &[&str]) -> Line {
+    Line {
+        prefix: None,
+        command: command.to_string(),
+        params: params.iter().map(ToString::to_string).collect(),
+    }
+}
+
+async fn write(writer: &mut (impl AsyncWrite + Unpin), lines: &[Line]) -> Result<()> {
+    for line in lines {
+        writer.write_all(line.encode().as_bytes()).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The line that failed to send before, or else the next one queued.
+async fn next_line(pending: &mut Option<Line>, queue: &mut mpsc::Receiver<Line>) -> Option<Line> {
+    match pending.take() {
+        Some(line) => Some(line),
+        None => queue.recv().await,
+    }
+}
+
+async fn session(
+    config: &IrcConfig,
+    queue: &mut mpsc::Receiver<Line>,
+    pending: &mut Option<Line>,
+    relay: &Relay,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(connect(config).await?);
+    let mut lines = BufReader::new(reader).lines();
+    // Registration, with SASL negotiated before it completes.
+    let mut nick = config.nick.clone();
+    let mut hello = Vec::new();
+    if config.sasl.is_some() {
+        hello.push(command("CAP", &["REQ", "sasl"]));
+    }
+    hello.push(command("NICK", &[&nick]));
+    hello.push(command("USER", &[&config.nick, "0", "*", &config.nick]));
+    write(&mut writer, &hello).await?;
+
+    let mut registered = false;
+    loop {
+        tokio::select! {
+            read = tokio::time::timeout(READ_TIMEOUT, lines.next_line()) => {
+                let Some(text) = read.context("server stopped responding")?? else {
+                    return Ok(());
+                };
+                let Some(line) = Line::parse(&text) else {
+                    continue;
+                };
+                let mut replies = Vec::new();
+                match line.command.as_str() {
+                    "PING" => {
+                        let params: Vec<_> = line.params.iter().map(String::as_str).collect();
+                        replies.push(command("PONG", &params));
+                    }
+                    "CAP" if line.params.get(1).map(String::as_str) == Some("ACK") => {
+                        replies.push(command("AUTHENTICATE", &["PLAIN"]));
+                    }
+                    "CAP" if line.params.get(1).map(String::as_str) == Some("NAK") => {
+                        bail!("server does not support SASL");
+                    }
+                    "AUTHENTICATE" if line.params.first().map(String::as_str) == Some("+") => {
+                        let sasl = config.sasl.as_ref().context("SASL not configured")?;
+                        let credentials = format!(
+                            "{}\0{}\0{}",
+                            sasl.username, sasl.username, sasl.password
+                        );
+                        replies.push(command("AUTHENTICATE", &[&base64::encode(credentials)]));
+                    }
+                    // Logged in.
+                    "903" => replies.push(command("CAP", &["END"])),
+                    "904" | "905" => bail!("SASL authentication failed"),
+                    // Nick in use, before registration completed.
+                    "433" if !registered => {
+                        nick.push('_');
+                        replies.push(command("NICK", &[&nick]));
+                    }
+                    "NICK" if line.nick() == Some(nick.as_str()) => {
+                        if let Some(new) = line.params.first() {
+                            nick = new.clone();
+                        }
+                    }
+                    // Welcome.
+                    "001" => {
+                        registered = true;
+                        if let Some(given) = line.params.first() {
+                            nick = given.clone();
+                        }
+                        info!(network = config.name, nick, "registered on IRC");
+                        for channel in &config.channels {
+                            replies.push(command("JOIN", &[channel]));
+                        }
+                    }
+                    "PRIVMSG" => {
+                        if let Some(message) = bridged(config, &nick, &line) {
+                            relay.relay(message).await;
+                        }
+                    }
+                    "ERROR" => {
+                        debug!(?line, network = config.name, "server closed the connection");
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+                write(&mut writer, &replies).await?;
+            }
+            Some(line) = next_line(pending, queue), if registered => {
+                // Kept until written, to be sent again after reconnecting otherwise.
+                let line = pending.insert(line);
+                write(&mut writer, std::slice::from_ref(line)).await?;
+                *pending = None;
+                tokio::time::sleep(SEND_DELAY).await;
+            }
+        }
+    }
+}
+
+/// The message of a `PRIVMSG` to a configured channel, unless we sent it.
+fn bridged(config: &IrcConfig, nick: &str, line: &Line) -> Option<BridgedMessage> {
+    let sender = line.nick()?;
+    let [channel, text] = line.params.as_slice() else {
+        return None;
+    };
+    if sender.eq_ignore_ascii_case(nick) {
+        return None;
+    }
+    // Servers may spell the channel differently, but groups are identified by the configured
+    // spelling.
+    let channel = config
+        .channels
+        .iter()
+        .find(|configured| configured.eq_ignore_ascii_case(channel))?;
+    let text = match text.strip_prefix("\u{1}ACTION ") {
+        Some(action) => format!("* {} {}", sender, action.trim_end_matches('\u{1}')),
+        // Other CTCP requests are not meant for humans.
+        None if text.starts_with('\u{1}') => return None,
+        None => text.clone(),
+    };
+    Some(BridgedMessage {
+        source: Group {
+            im: IM::Irc,
+            id: format!("{}/{}", config.name, channel),
+        },
+        sender_id: sender.to_string(),
+        sender: sender.to_string(),
+        avatar: None,
+        text,
+    })
+}
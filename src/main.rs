@@ -1,95 +1,206 @@
 #![allow(clippy::module_name_repetitions)]
 
-use std::future::Future;
-use std::pin::Pin;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use once_cell::sync::OnceCell;
-use proc_qq::re_exports::bytes::Bytes;
-use proc_qq::re_exports::ricq::version::ANDROID_WATCH;
-use proc_qq::Authentication::QRCode;
+use clap::Parser;
+use dptree::di::DependencyMap;
+use futures::future::join_all;
+use proc_qq::ClientBuilder;
 use proc_qq::DeviceSource::JsonFile;
-use proc_qq::{ClientBuilder, ShowQR};
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
-use crate::config::Config;
-use crate::db::DB;
+use crate::accounts::Accounts;
+use crate::bridge::{Backend, Backends, Relay};
+use crate::cli::{Cli, Command};
+use crate::config::{AccountConfig, Config};
+use crate::db::{DB, IM};
+use crate::discord::Discord;
+use crate::dp_helper::EVHandler;
+use crate::handlers::approval::JoinRequests;
 use crate::handlers::auth::{Token, OTP};
 use crate::handlers::handler;
+use crate::http::AppState;
+use crate::irc::Irc;
+use crate::matrix::Matrix;
+use crate::status::{AccountIndex, BotStatus, ForwardStats};
 
+mod accounts;
+mod backup;
+mod bridge;
+mod cli;
+mod client;
 mod config;
 mod db;
+mod discord;
 mod dp_helper;
 mod handlers;
-
-static CONFIG: OnceCell<Config> = OnceCell::new();
-
-fn mail_qrcode(f: Bytes) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-    let client = reqwest::Client::new();
-    let config = CONFIG.get().cloned().unwrap().qrcode.unwrap();
-    let form = reqwest::multipart::Form::new()
-        .text("from", format!("IM Bridge <mailgun@{}>", config.domain))
-        .text("to", config.to)
-        .text("subject", "IM Bridge - QR Code")
-        .text("text", "Please scan the qr code to login")
-        .part(
-            "attachment",
-            reqwest::multipart::Part::bytes(f.to_vec()).file_name("qrcode.png"),
-        );
-    Box::pin(async move {
-        let resp = client
-            .post(&format!(
-                "https://api.mailgun.net/v3/{}/messages",
-                config.domain
-            ))
-            .basic_auth("api", Some(&config.apikey))
-            .multipart(form)
-            .send()
-            .await?
-            .text()
-            .await?;
-        info!(resp, "QR code sent");
-        Ok(())
-    })
-}
-
-fn qr_method() -> ShowQR {
-    let by_mail = CONFIG.get().unwrap().qrcode.is_some();
-    if by_mail {
-        ShowQR::Custom(Box::pin(mail_qrcode))
-    } else {
-        ShowQR::PrintToConsole
-    }
-}
+mod http;
+mod irc;
+mod matrix;
+mod metrics;
+mod onebot;
+mod qr;
+mod status;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    // Keep stdout clean for commands that write documents to it.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
-    let config = Config::from_env();
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref())?;
     debug!(?config, "config loaded");
-    CONFIG.set(config.clone()).unwrap();
 
-    let token = Token::default();
-    let otp = OTP::default();
-    let db = DB::connect(&config.mongodb.uri, &config.mongodb.database).await?;
-    info!("Manage token: {}", token);
+    let db = db::connect(&config).await?;
+    if let Some(Command::Offline(command)) = cli.command {
+        return command.run(db).await;
+    }
+    db.watch();
+    let db: DB = db;
+
+    let config = Arc::new(config);
+    let token = Token::load(&db).await?;
+    let otp = OTP::new(db.clone());
+    let requests = JoinRequests::new(Duration::from_secs(config.join.ttl));
+    let account_configs: Vec<_> = std::iter::once(config.primary_account())
+        .chain(config.accounts.iter().cloned())
+        .collect();
+    let status = BotStatus::new(account_configs.len());
+    let accounts = Accounts::default();
+    let stats = ForwardStats::default();
+    let mut enabled: HashMap<IM, Arc<dyn Backend>> = HashMap::new();
+    if let Some(discord) = &config.discord {
+        enabled.insert(IM::Discord, Arc::new(Discord::new(discord)));
+    }
+    let matrix = match &config.matrix {
+        Some(matrix) => {
+            let matrix = Arc::new(Matrix::new(matrix)?);
+            enabled.insert(IM::Matrix, matrix.clone());
+            Some(matrix)
+        }
+        None => None,
+    };
+    let (irc, irc_connections) = Irc::new(&config.irc);
+    if !config.irc.is_empty() {
+        enabled.insert(IM::Irc, Arc::new(irc));
+    }
+    let backends = Backends::new(enabled);
+    let relay = Relay {
+        db: db.clone(),
+        accounts: accounts.clone(),
+        backends: backends.clone(),
+        stats: stats.clone(),
+    };
+    if let Some(discord) = config.discord.clone() {
+        tokio::spawn(discord::run(discord, relay.clone()));
+    }
+    irc_connections.spawn(relay.clone());
+    if let (Some(config), Some(matrix)) = (config.matrix.clone(), matrix) {
+        tokio::spawn(async move {
+            if let Err(e) = matrix::serve(config, matrix, relay).await {
+                error!(?e, "Matrix application service stopped");
+            }
+        });
+    }
+    if let Some(http) = config.http.clone() {
+        let state = AppState {
+            db: db.clone(),
+            otp: otp.clone(),
+            status: status.clone(),
+            accounts: accounts.clone(),
+            stats: stats.clone(),
+            token: http.token.clone().unwrap_or_else(|| token.to_string()),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(http, state).await {
+                error!(?e, "HTTP server stopped");
+            }
+        });
+    }
+    let deps = dptree::deps![
+        token,
+        otp,
+        requests,
+        db,
+        status.clone(),
+        accounts,
+        stats,
+        backends,
+        config.clone()
+    ];
+    let handler = handler();
+    join_all(account_configs.iter().enumerate().map(|(index, account)| {
+        let mut deps = deps.clone();
+        deps.insert(AccountIndex(index));
+        supervise(&config, account, deps, handler.clone(), status.clone())
+    }))
+    .await;
+    Ok(())
+}
+
+/// Build a client for the account and run it until the connection drops.
+async fn run_client(
+    config: &Config,
+    account: &AccountConfig,
+    deps: DependencyMap,
+    handler: EVHandler,
+) -> Result<()> {
+    if let Some(onebot) = &account.onebot {
+        return onebot::run(onebot, deps, handler).await;
+    }
+    let qrcode = account.qrcode.as_ref().unwrap_or(&config.qrcode);
     let client = ClientBuilder::new()
-        .priority_session(
-            std::env::var("SESSION_FILE").unwrap_or_else(|_| "session.token".to_string()),
-        )
-        .authentication(QRCode)
-        .device(JsonFile(
-            std::env::var("DEVICE_FILE").unwrap_or_else(|_| "device.json".to_string()),
-        ))
-        .version(&ANDROID_WATCH)
-        .modules(vec![dp_helper::module(
-            dptree::deps![token, otp, db],
-            handler(),
-        )])
-        .show_rq(Some(qr_method()))
+        .priority_session(account.session_file.clone())
+        .authentication(account.auth.authentication()?)
+        .device(JsonFile(account.device_file.clone()))
+        .version(account.protocol.version())
+        .modules(vec![dp_helper::module(deps, handler)])
+        .show_rq(Some(qr::show_qr(qrcode)))
         .build()
         .await?;
     client.start().await??;
     Ok(())
 }
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// A connection that lasted this long resets the backoff.
+const STABLE_CONNECTION: Duration = Duration::from_secs(10 * 60);
+
+/// Keep the client of an account connected, reconnecting with exponential backoff whenever it stops.
+///
+/// Every reconnect logs in with the session token saved by the previous login, so a QR code is only
+/// requested again if the session is no longer valid. Dependencies, and thus the database
+/// connection and all in-memory state, are shared across reconnects.
+async fn supervise(
+    config: &Config,
+    account: &AccountConfig,
+    deps: DependencyMap,
+    handler: EVHandler,
+    status: BotStatus,
+) {
+    let index = deps.get::<AccountIndex>();
+    let accounts = deps.get::<Accounts>();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let started = Instant::now();
+        match run_client(config, account, deps.clone(), handler.clone()).await {
+            Ok(()) => warn!(session = %account.session_file, "client disconnected"),
+            Err(e) => error!(?e, session = %account.session_file, "client stopped"),
+        }
+        if let Some(uin) = status.set_offline(*index) {
+            accounts.set_offline(uin);
+        }
+        if started.elapsed() >= STABLE_CONNECTION {
+            backoff = INITIAL_BACKOFF;
+        }
+        info!(?backoff, "reconnecting");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
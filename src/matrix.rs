@@ -0,0 +1,403 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::bridge::{Backend, BridgedMessage};
+use crate::config::MatrixConfig;
+
+pub use crate::matrix::appservice::serve;
+
+mod appservice;
+
+/// Profile last set for a puppet, as it came from the source IM.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Profile {
+    name: String,
+    avatar: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MatrixError {
+    #[serde(default)]
+    errcode: String,
+}
+
+/// Client-server API client of the application service.
+///
+/// Every sender bridged into Matrix is puppeted by a virtual user of the application service,
+/// registered on first use and kept in sync with the sender's name and avatar.
+pub struct Matrix {
+    http: reqwest::Client,
+    homeserver: Url,
+    token: String,
+    domain: String,
+    bot: String,
+    prefix: String,
+    puppets: Mutex<HashMap<String, Profile>>,
+    /// Puppets known to be in a room, as `(user, room)`.
+    joined: Mutex<HashSet<(String, String)>>,
+    /// Transaction ids must be unique per user across restarts.
+    started: u128,
+    txn: AtomicU64,
+}
+
+impl Matrix {
+    pub fn new(config: &MatrixConfig) -> Result<Self> {
+        let homeserver: Url = config
+            .homeserver
+            .parse()
+            .context("invalid matrix.homeserver")?;
+        if homeserver.cannot_be_a_base() {
+            bail!("invalid matrix.homeserver: {}", homeserver);
+        }
+        Ok(Self {
+            http: reqwest::Client::new(),
+            homeserver,
+            token: config.tokens.appservice.clone(),
+            domain: config.domain.clone(),
+            bot: config.bot.clone(),
+            prefix: config.prefix.clone(),
+            puppets: Mutex::default(),
+            joined: Mutex::default(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            txn: AtomicU64::new(0),
+        })
+    }
+    fn request(&self, method: Method, segments: &[&str], user: Option<&str>) -> RequestBuilder {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .expect("checked in new")
+            .pop_if_empty()
+            .extend(segments);
+        let request = self.http.request(method, url).bearer_auth(&self.token);
+        match user {
+            // Act as a puppet instead of the bot.
+            Some(user) => request.query(&[("user_id", user)]),
+            None => request,
+        }
+    }
+    fn user_id(&self, localpart: &str) -> String {
+        format!("@{}:{}", localpart, self.domain)
+    }
+    /// User id of the bot.
+    pub fn bot_id(&self) -> String {
+        self.user_id(&self.bot)
+    }
+    /// Make the bot join a room it was invited to.
+    pub async fn join(&self, room: &str) -> Result<()> {
+        self.request(
+            Method::POST,
+            &["_matrix", "client", "v3", "rooms", room, "join"],
+            None,
+        )
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+    /// Whether the user is the bot or one of its puppets, whose messages must not be bridged.
+    pub fn is_own(&self, user_id: &str) -> bool {
+        let Some((localpart, domain)) = user_id
+            .strip_prefix('@')
+            .and_then(|user| user.split_once(':')) else {
+            return false;
+        };
+        domain == self.domain && (localpart == self.bot || localpart.starts_with(&self.prefix))
+    }
+    /// User id of the puppet of a message's sender.
+    fn puppet(&self, message: &BridgedMessage) -> (String, String) {
+        let localpart = format!(
+            "{}{}_{}",
+            self.prefix,
+            message.source.im.as_str().to_ascii_lowercase(),
+            escape(&message.sender_id)
+        );
+        let user_id = self.user_id(&localpart);
+        (localpart, user_id)
+    }
+    /// Register the puppet if needed and update its profile if the sender's changed.
+    async fn ensure_puppet(&self, message: &BridgedMessage) -> Result<String> {
+        let (localpart, user_id) = self.puppet(message);
+        let wanted = Profile {
+            name: message.sender.clone(),
+            avatar: message.avatar.clone(),
+        };
+        let current = self.puppets.lock().get(&user_id).cloned();
+        if current.as_ref() == Some(&wanted) {
+            return Ok(user_id);
+        }
+        if current.is_none() {
+            self.register(&localpart).await?;
+        }
+        if current.as_ref().map(|profile| &profile.name) != Some(&wanted.name) {
+            self.request(
+                Method::PUT,
+                &[
+                    "_matrix",
+                    "client",
+                    "v3",
+                    "profile",
+                    &user_id,
+                    "displayname",
+                ],
+                Some(&user_id),
+            )
+            .json(&json!({ "displayname": wanted.name }))
+            .send()
+            .await?
+            .error_for_status()?;
+        }
+        if current.and_then(|profile| profile.avatar) != wanted.avatar {
+            if let Some(avatar) = &wanted.avatar {
+                // A missing avatar is not worth dropping the message for.
+                if let Err(e) = self.set_avatar(&user_id, avatar).await {
+                    warn!(?e, user_id, "failed to set puppet avatar");
+                }
+            }
+        }
+        self.puppets.lock().insert(user_id.clone(), wanted);
+        Ok(user_id)
+    }
+    async fn register(&self, localpart: &str) -> Result<()> {
+        let response = self
+            .request(Method::POST, &["_matrix", "client", "v3", "register"], None)
+            .json(&json!({ "type": "m.login.application_service", "username": localpart }))
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let error: MatrixError = response.json().await.unwrap_or_default();
+        if error.errcode == "M_USER_IN_USE" {
+            return Ok(());
+        }
+        bail!(
+            "failed to register {}: {} {}",
+            localpart,
+            status,
+            error.errcode
+        );
+    }
+    /// Upload the image at `url` to the homeserver and make it the user's avatar.
+    async fn set_avatar(&self, user_id: &str, url: &str) -> Result<()> {
+        #[derive(Deserialize)]
+        struct Uploaded {
+            content_uri: String,
+        }
+        let image = self.http.get(url).send().await?.error_for_status()?;
+        let content_type = image
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("image/jpeg")
+            .to_string();
+        let uploaded: Uploaded = self
+            .request(Method::POST, &["_matrix", "media", "v3", "upload"], None)
+            .header(CONTENT_TYPE, content_type)
+            .body(image.bytes().await?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.request(
+            Method::PUT,
+            &["_matrix", "client", "v3", "profile", user_id, "avatar_url"],
+            Some(user_id),
+        )
+        .json(&json!({ "avatar_url": uploaded.content_uri }))
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+    async fn ensure_joined(&self, user_id: &str, room: &str) -> Result<()> {
+        let key = (user_id.to_string(), room.to_string());
+        if self.joined.lock().contains(&key) {
+            return Ok(());
+        }
+        // Rooms need not be public: the bot, which must be a member, invites the puppet first.
+        let invited = self
+            .request(
+                Method::POST,
+                &["_matrix", "client", "v3", "rooms", room, "invite"],
+                None,
+            )
+            .json(&json!({ "user_id": user_id }))
+            .send()
+            .await?;
+        if !invited.status().is_success() {
+            // Also the case if the puppet is in the room already.
+            debug!(status = %invited.status(), user_id, room, "failed to invite puppet");
+        }
+        self.request(
+            Method::POST,
+            &["_matrix", "client", "v3", "rooms", room, "join"],
+            Some(user_id),
+        )
+        .send()
+        .await?
+        .error_for_status()?;
+        self.joined.lock().insert(key);
+        Ok(())
+    }
+    async fn send_message(&self, room: &str, user: Option<&str>, content: Value) -> Result<()> {
+        let txn = format!(
+            "{}-{}",
+            self.started,
+            self.txn.fetch_add(1, Ordering::Relaxed)
+        );
+        self.request(
+            Method::PUT,
+            &[
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room,
+                "send",
+                "m.room.message",
+                &txn,
+            ],
+            user,
+        )
+        .json(&content)
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+    /// Display name of a room member, falling back to the user id.
+    pub async fn display_name(&self, room: &str, user_id: &str) -> String {
+        #[derive(Deserialize)]
+        struct Member {
+            displayname: Option<String>,
+        }
+        let member = async {
+            let member: Member = self
+                .request(
+                    Method::GET,
+                    &[
+                        "_matrix",
+                        "client",
+                        "v3",
+                        "rooms",
+                        room,
+                        "state",
+                        "m.room.member",
+                        user_id,
+                    ],
+                    None,
+                )
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok::<_, anyhow::Error>(member)
+        };
+        match member.await {
+            Ok(Member {
+                displayname: Some(name),
+            }) => name,
+            Ok(_) => user_id.to_string(),
+            Err(e) => {
+                debug!(?e, room, user_id, "failed to get display name");
+                user_id.to_string()
+            }
+        }
+    }
+}
+
+/// Escape an id for use in a user id localpart, which only allows a few characters.
+fn escape(id: &str) -> String {
+    let mut escaped = String::new();
+    for byte in id.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("={:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+#[async_trait]
+impl Backend for Matrix {
+    async fn send(&self, room: &str, message: &BridgedMessage) -> Result<()> {
+        let user_id = self.ensure_puppet(message).await?;
+        self.ensure_joined(&user_id, room).await?;
+        let content = json!({ "msgtype": "m.text", "body": message.text });
+        let result = self.send_message(room, Some(&user_id), content).await;
+        if result.is_err() {
+            // Maybe kicked. Join again next time.
+            self.joined
+                .lock()
+                .remove(&(user_id.clone(), room.to_string()));
+        }
+        result
+    }
+    async fn send_notice(&self, room: &str, text: &str) -> Result<()> {
+        self.send_message(room, None, json!({ "msgtype": "m.notice", "body": text }))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, Matrix};
+    use crate::bridge::BridgedMessage;
+    use crate::config::{MatrixConfig, MatrixTokens};
+    use crate::db::Group;
+
+    fn matrix() -> Matrix {
+        Matrix::new(&MatrixConfig {
+            homeserver: "https://matrix.example.org".to_string(),
+            domain: "example.org".to_string(),
+            bind: "127.0.0.1:9000".parse().unwrap(),
+            tokens: MatrixTokens {
+                appservice: "as".to_string(),
+                homeserver: "hs".to_string(),
+            },
+            bot: "imbridge".to_string(),
+            prefix: "imbridge_".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn ids_are_escaped_for_localparts() {
+        assert_eq!(escape("12345"), "12345");
+        assert_eq!(escape("Ab_c"), "=41b=5fc");
+    }
+
+    #[test]
+    fn puppets_and_bot_are_own_users() {
+        let matrix = matrix();
+        let (_, puppet) = matrix.puppet(&BridgedMessage {
+            source: Group::from_qq(100),
+            sender_id: "42".to_string(),
+            sender: "Alice".to_string(),
+            avatar: None,
+            text: String::new(),
+        });
+        assert_eq!(puppet, "@imbridge_qq_42:example.org");
+        assert!(matrix.is_own(&puppet));
+        assert!(matrix.is_own("@imbridge:example.org"));
+        assert_eq!(matrix.bot_id(), "@imbridge:example.org");
+        assert!(!matrix.is_own("@alice:example.org"));
+        assert!(!matrix.is_own("@imbridge_qq_42:other.org"));
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::bridge::{BridgedMessage, Relay};
+use crate::config::MatrixConfig;
+use crate::db::{Group, IM};
+use crate::handlers::auth::secret_eq;
+use crate::matrix::Matrix;
+
+/// Number of transaction ids remembered to ignore retries of transactions already handled.
+const SEEN_CAPACITY: usize = 256;
+
+#[derive(Clone)]
+struct AppState {
+    matrix: Arc<Matrix>,
+    relay: Relay,
+    /// The `hs_token` the homeserver authenticates with.
+    token: String,
+    seen: Arc<Mutex<VecDeque<String>>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    // Defaulted like the rest, so that one unexpected event does not make the homeserver retry
+    // the whole transaction forever.
+    #[serde(default)]
+    room_id: String,
+    #[serde(default)]
+    sender: String,
+    /// The user a membership event is about.
+    #[serde(default)]
+    state_key: Option<String>,
+    #[serde(default)]
+    content: Value,
+}
+
+/// Message types bridged as text.
+const TEXT_TYPES: &[&str] = &["m.text", "m.notice", "m.emote"];
+
+impl Event {
+    /// Whether this invites the user into the room.
+    fn invites(&self, user_id: &str) -> bool {
+        self.kind == "m.room.member"
+            && self.state_key.as_deref() == Some(user_id)
+            && self.content["membership"] == "invite"
+    }
+    /// Text of a message event that should be bridged.
+    fn text(&self) -> Option<&str> {
+        if self.kind != "m.room.message" {
+            return None;
+        }
+        // Edits repeat the whole message, which would be bridged twice.
+        if self.content["m.relates_to"]["rel_type"] == "m.replace" {
+            return None;
+        }
+        let msgtype = self.content["msgtype"].as_str()?;
+        if !TEXT_TYPES.contains(&msgtype) {
+            return None;
+        }
+        self.content["body"]
+            .as_str()
+            .filter(|body| !body.is_empty())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// Homeservers send the token as a bearer token or, before spec v1.4, as a query parameter.
+async fn authorize<B>(
+    State(state): State<AppState>,
+    Query(query): Query<TokenQuery>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let header = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(ToString::to_string);
+    match header.or(query.access_token) {
+        Some(given) if secret_eq(&given, &state.token) => next.run(req).await,
+        Some(_) => error(StatusCode::FORBIDDEN, "M_FORBIDDEN"),
+        None => error(StatusCode::UNAUTHORIZED, "M_UNAUTHORIZED"),
+    }
+}
+
+fn error(status: StatusCode, errcode: &str) -> Response {
+    (status, Json(json!({ "errcode": errcode }))).into_response()
+}
+
+async fn transaction(
+    State(state): State<AppState>,
+    Path(txn): Path<String>,
+    Json(transaction): Json<Transaction>,
+) -> Json<Value> {
+    if state.seen.lock().contains(&txn) {
+        debug!(txn, "ignoring repeated transaction");
+        return Json(json!({}));
+    }
+    let bot = state.matrix.bot_id();
+    for event in transaction.events {
+        if event.invites(&bot) {
+            info!(
+                room = %event.room_id,
+                inviter = %event.sender,
+                "invited, joining"
+            );
+            if let Err(e) = state.matrix.join(&event.room_id).await {
+                warn!(?e, room = %event.room_id, "failed to join room");
+            }
+            continue;
+        }
+        let Some(text) = event.text() else {
+            continue;
+        };
+        if state.matrix.is_own(&event.sender) {
+            continue;
+        }
+        let message = BridgedMessage {
+            source: Group {
+                im: IM::Matrix,
+                id: event.room_id.clone(),
+            },
+            sender_id: event.sender.clone(),
+            sender: state
+                .matrix
+                .display_name(&event.room_id, &event.sender)
+                .await,
+            avatar: None,
+            text: text.to_string(),
+        };
+        state.relay.relay(message).await;
+    }
+    // Only now, so that a transaction interrupted before its events were handled is handled again
+    // when the homeserver retries it.
+    let mut seen = state.seen.lock();
+    if seen.len() == SEEN_CAPACITY {
+        seen.pop_front();
+    }
+    seen.push_back(txn);
+    Json(json!({}))
+}
+
+/// Puppets are registered when first needed, so there is nothing to provision on queries.
+async fn user(State(state): State<AppState>, Path(user_id): Path<String>) -> Response {
+    if state.matrix.is_own(&user_id) {
+        Json(json!({})).into_response()
+    } else {
+        error(StatusCode::NOT_FOUND, "M_NOT_FOUND")
+    }
+}
+
+async fn room() -> Response {
+    error(StatusCode::NOT_FOUND, "M_NOT_FOUND")
+}
+
+/// Listen for the events the homeserver pushes to the application service.
+pub async fn serve(config: MatrixConfig, matrix: Arc<Matrix>, relay: Relay) -> Result<()> {
+    let state = AppState {
+        matrix,
+        relay,
+        token: config.tokens.homeserver.clone(),
+        seen: Arc::default(),
+    };
+    let api = Router::new()
+        .route("/transactions/:txn", put(transaction))
+        .route("/users/:user", get(user))
+        .route("/rooms/:alias", get(room));
+    let app = Router::new()
+        .nest("/_matrix/app/v1", api.clone())
+        // Paths of homeservers predating spec v1.1.
+        .merge(api)
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state);
+    info!(bind = %config.bind, "Matrix application service listening");
+    axum::Server::bind(&config.bind)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Event;
+
+    fn event(content: serde_json::Value) -> Event {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn invites_of_the_bot_are_recognized() {
+        let invite = |state_key: &str, membership: &str| -> Event {
+            serde_json::from_value(json!({
+                "type": "m.room.member",
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "state_key": state_key,
+                "content": { "membership": membership },
+            }))
+            .unwrap()
+        };
+        let bot = "@imbridge:example.org";
+        assert!(invite(bot, "invite").invites(bot));
+        assert!(!invite(bot, "join").invites(bot));
+        assert!(!invite("@bob:example.org", "invite").invites(bot));
+        assert!(!event(json!({ "msgtype": "m.text", "body": "hello" })).invites(bot));
+    }
+
+    #[test]
+    fn only_text_messages_are_bridged() {
+        assert_eq!(
+            event(json!({ "msgtype": "m.text", "body": "hello" })).text(),
+            Some("hello")
+        );
+        assert_eq!(
+            event(json!({ "msgtype": "m.image", "body": "cat.png" })).text(),
+            None
+        );
+        let edit = event(json!({
+            "msgtype": "m.text",
+            "body": "* hello",
+            "m.relates_to": { "rel_type": "m.replace" },
+        }));
+        assert_eq!(edit.text(), None);
+    }
+}
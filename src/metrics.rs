@@ -0,0 +1,97 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, Encoder, Histogram,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+
+pub static UPDATES_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "im_bridge_updates_received_total",
+        "Updates received from QQ, by kind.",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static MESSAGES_FORWARDED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "im_bridge_messages_forwarded_total",
+        "Messages forwarded, by target IM.",
+        &["im"]
+    )
+    .unwrap()
+});
+
+pub static FORWARD_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "im_bridge_forward_failures_total",
+        "Messages that failed to be forwarded, by error class.",
+        &["class"]
+    )
+    .unwrap()
+});
+
+pub static COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "im_bridge_commands_total",
+        "Commands invoked, by command name.",
+        &["command"]
+    )
+    .unwrap()
+});
+
+pub static OTP_ISSUED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("im_bridge_otp_issued_total", "One-time passwords issued.").unwrap()
+});
+
+pub static OTP_VERIFIED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "im_bridge_otp_verified_total",
+        "One-time password verifications, by result.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static FORWARD_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "im_bridge_forward_latency_seconds",
+        "Time taken to forward a message to one target."
+    )
+    .unwrap()
+});
+
+pub static FORWARD_TARGETS_QUERY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "im_bridge_forward_targets_query_seconds",
+        "Time taken to look up the forward targets of a group."
+    )
+    .unwrap()
+});
+
+/// Classify a forward error for the failure counter: the variant name for protocol errors.
+pub fn error_class(e: &anyhow::Error) -> String {
+    use proc_qq::re_exports::ricq::RQError;
+
+    if let Some(e) = e.downcast_ref::<RQError>() {
+        let debug = format!("{:?}", e);
+        debug
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or("RQError")
+            .to_string()
+    } else if e.is::<std::num::ParseIntError>() {
+        "InvalidTarget".to_string()
+    } else {
+        "Other".to_string()
+    }
+}
+
+/// Render all registered metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
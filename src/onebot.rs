@@ -0,0 +1,451 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use proc_qq::re_exports::ricq::msg::elem::{At, Face, RQElem, Text};
+use proc_qq::re_exports::ricq::msg::MessageChain;
+use proc_qq::re_exports::ricq::structs::MessageReceipt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::client::QQClient;
+use crate::config::{OneBotConfig, OneBotVersion};
+
+pub use crate::onebot::session::run;
+
+mod session;
+
+/// Ids remembered to translate between OneBot ids and the numbers handlers key on.
+const IDS_CAPACITY: usize = 4096;
+
+/// Bounded two-way map between OneBot ids, which are strings in v12, and the numbers ricq uses
+/// for message seqs and friend requests.
+#[derive(Debug, Default)]
+struct Ids {
+    next: i32,
+    order: VecDeque<i32>,
+    numbers: HashMap<String, i32>,
+    ids: HashMap<i32, String>,
+}
+
+impl Ids {
+    fn number(&mut self, id: &str) -> i32 {
+        if let Some(number) = self.numbers.get(id) {
+            return *number;
+        }
+        if self.order.len() == IDS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                if let Some(id) = self.ids.remove(&oldest) {
+                    self.numbers.remove(&id);
+                }
+            }
+        }
+        self.next = self.next.wrapping_add(1);
+        let number = self.next;
+        self.order.push_back(number);
+        self.numbers.insert(id.to_string(), number);
+        self.ids.insert(number, id.to_string());
+        number
+    }
+    fn id(&self, number: i32) -> Option<String> {
+        self.ids.get(&number).cloned()
+    }
+}
+
+/// An integer, which OneBot v12 passes as a string.
+fn int(value: &Value) -> Option<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|text| text.parse().ok()))
+}
+
+/// An id, which OneBot v11 passes as a number.
+fn string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    status: String,
+    #[serde(default)]
+    retcode: i64,
+    #[serde(default)]
+    data: Value,
+    /// `message` in v12, `msg` in v11.
+    #[serde(default, alias = "msg")]
+    message: String,
+}
+
+/// Client of an external OneBot implementation, calling actions on its HTTP API.
+pub struct OneBot {
+    http: reqwest::Client,
+    version: OneBotVersion,
+    api: String,
+    token: Option<String>,
+    /// Uin of the account, once logged in.
+    uin: AtomicI64,
+    messages: Mutex<Ids>,
+    /// Flags of pending friend requests.
+    requests: Mutex<Ids>,
+    group_names: Mutex<HashMap<i64, String>>,
+}
+
+impl OneBot {
+    pub fn new(config: &OneBotConfig) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            version: config.version,
+            api: config.api.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+            uin: AtomicI64::new(0),
+            messages: Mutex::default(),
+            requests: Mutex::default(),
+            group_names: Mutex::default(),
+        }
+    }
+    async fn call(&self, action: &str, params: Value) -> Result<Value> {
+        let request = match self.version {
+            OneBotVersion::V11 => self
+                .http
+                .post(format!("{}/{}", self.api, action))
+                .json(&params),
+            OneBotVersion::V12 => self
+                .http
+                .post(&self.api)
+                .json(&json!({ "action": action, "params": params })),
+        };
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response: Response = request.send().await?.error_for_status()?.json().await?;
+        // v11 answers `async` to actions it queued.
+        if response.status != "ok" && response.status != "async" {
+            bail!(
+                "OneBot action {} failed: {} {}",
+                action,
+                response.retcode,
+                response.message
+            );
+        }
+        Ok(response.data)
+    }
+    /// A user or group id as the protocol version passes it.
+    fn id(&self, id: i64) -> Value {
+        match self.version {
+            OneBotVersion::V11 => json!(id),
+            OneBotVersion::V12 => json!(id.to_string()),
+        }
+    }
+    /// Look up the uin of the logged in account.
+    async fn login(&self) -> Result<i64> {
+        let action = match self.version {
+            OneBotVersion::V11 => "get_login_info",
+            OneBotVersion::V12 => "get_self_info",
+        };
+        let info = self.call(action, json!({})).await?;
+        let uin = int(&info["user_id"]).context("login info without user_id")?;
+        self.uin.store(uin, Ordering::Relaxed);
+        Ok(uin)
+    }
+    /// The seq handlers know a message by.
+    fn message_seq(&self, id: &Value) -> Option<i32> {
+        string(id).map(|id| self.messages.lock().number(&id))
+    }
+    fn friend_request_seq(&self, flag: &str) -> i64 {
+        self.requests.lock().number(flag).into()
+    }
+    async fn group_name(&self, group: i64) -> String {
+        if let Some(name) = self.group_names.lock().get(&group) {
+            return name.clone();
+        }
+        let info = self
+            .call("get_group_info", json!({ "group_id": self.id(group) }))
+            .await;
+        match info
+            .ok()
+            .and_then(|info| info["group_name"].as_str().map(ToString::to_string))
+        {
+            Some(name) => {
+                self.group_names.lock().insert(group, name.clone());
+                name
+            }
+            None => group.to_string(),
+        }
+    }
+    async fn send(&self, detail_type: &str, target: i64, message: MessageChain) -> Result<Value> {
+        let message = self.segments(message).await?;
+        match self.version {
+            OneBotVersion::V11 => {
+                let (action, key) = match detail_type {
+                    "group" => ("send_group_msg", "group_id"),
+                    _ => ("send_private_msg", "user_id"),
+                };
+                self.call(action, json!({ key: target, "message": message }))
+                    .await
+            }
+            OneBotVersion::V12 => {
+                let key = match detail_type {
+                    "group" => "group_id",
+                    _ => "user_id",
+                };
+                self.call(
+                    "send_message",
+                    json!({
+                        "detail_type": detail_type,
+                        key: target.to_string(),
+                        "message": message,
+                    }),
+                )
+                .await
+            }
+        }
+    }
+    /// Message segments of a message chain. Elements OneBot has no segment for are sent as text.
+    async fn segments(&self, chain: MessageChain) -> Result<Vec<Value>> {
+        let v11 = self.version == OneBotVersion::V11;
+        let mut segments = Vec::new();
+        for elem in chain.0 {
+            let fallback = MessageChain(vec![elem.clone()]).to_string();
+            let segment = match RQElem::from(elem) {
+                RQElem::Text(text) => text_segment(text.content),
+                // Target 0 mentions everyone.
+                RQElem::At(at) if v11 => {
+                    let qq = if at.target == 0 {
+                        "all".to_string()
+                    } else {
+                        at.target.to_string()
+                    };
+                    json!({ "type": "at", "data": { "qq": qq } })
+                }
+                RQElem::At(at) if at.target == 0 => json!({ "type": "mention_all", "data": {} }),
+                RQElem::At(at) => {
+                    json!({ "type": "mention", "data": { "user_id": at.target.to_string() } })
+                }
+                RQElem::Face(face) if v11 => {
+                    json!({ "type": "face", "data": { "id": face.index.to_string() } })
+                }
+                RQElem::GroupImage(image) => self.image(image.url()).await?,
+                RQElem::FriendImage(image) => self.image(image.url()).await?,
+                _ if fallback.is_empty() => continue,
+                _ => text_segment(fallback),
+            };
+            segments.push(segment);
+        }
+        Ok(segments)
+    }
+    async fn image(&self, url: String) -> Result<Value> {
+        Ok(match self.version {
+            OneBotVersion::V11 => json!({ "type": "image", "data": { "file": url } }),
+            // Files must be uploaded before they can be sent.
+            OneBotVersion::V12 => {
+                let uploaded = self
+                    .call(
+                        "upload_file",
+                        json!({ "type": "url", "name": "image", "url": url }),
+                    )
+                    .await?;
+                let file_id = string(&uploaded["file_id"]).context("upload without file_id")?;
+                json!({ "type": "image", "data": { "file_id": file_id } })
+            }
+        })
+    }
+}
+
+fn text_segment(text: String) -> Value {
+    json!({ "type": "text", "data": { "text": text } })
+}
+
+/// Message chain of received message segments. Only text, mentions, faces and image URLs are kept.
+fn chain(message: &Value) -> MessageChain {
+    let mut chain = MessageChain::default();
+    let segments = match message {
+        // v11 in the string format, with CQ codes and all.
+        Value::String(text) => {
+            chain.push(Text::new(text.clone()));
+            return chain;
+        }
+        Value::Array(segments) => segments,
+        _ => return chain,
+    };
+    for segment in segments {
+        let data = &segment["data"];
+        match segment["type"].as_str().unwrap_or_default() {
+            "text" => {
+                if let Some(text) = data["text"].as_str() {
+                    chain.push(Text::new(text.to_string()));
+                }
+            }
+            "mention_all" => chain.push(mention(0)),
+            "at" if data["qq"] == "all" => chain.push(mention(0)),
+            "at" => {
+                if let Some(target) = int(&data["qq"]) {
+                    chain.push(mention(target));
+                }
+            }
+            "mention" => {
+                if let Some(target) = int(&data["user_id"]) {
+                    chain.push(mention(target));
+                }
+            }
+            "face" => {
+                if let Some(id) = int(&data["id"]).and_then(|id| i32::try_from(id).ok()) {
+                    chain.push(Face::new(id));
+                }
+            }
+            "image" => {
+                if let Some(url) = data["url"].as_str() {
+                    chain.push(Text::new(url.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    chain
+}
+
+fn mention(target: i64) -> At {
+    At {
+        target,
+        display: if target == 0 {
+            "@all".to_string()
+        } else {
+            format!("@{}", target)
+        },
+    }
+}
+
+#[async_trait]
+impl QQClient for OneBot {
+    async fn uin(&self) -> i64 {
+        self.uin.load(Ordering::Relaxed)
+    }
+    async fn send_group_message(
+        &self,
+        group: i64,
+        message: MessageChain,
+    ) -> Result<MessageReceipt> {
+        let sent = self.send("group", group, message).await?;
+        let seq = self
+            .message_seq(&sent["message_id"])
+            .context("sent message without message_id")?;
+        Ok(MessageReceipt {
+            seqs: vec![seq],
+            rands: vec![0],
+            time: sent["time"].as_f64().unwrap_or_default() as i64,
+        })
+    }
+    async fn send_friend_message(&self, uin: i64, message: MessageChain) -> Result<()> {
+        self.send("private", uin, message).await?;
+        Ok(())
+    }
+    async fn group_admins(&self, group: i64) -> Result<Vec<i64>> {
+        let members = self
+            .call(
+                "get_group_member_list",
+                json!({ "group_id": self.id(group) }),
+            )
+            .await?;
+        let members = members.as_array().context("member list is not a list")?;
+        Ok(members
+            .iter()
+            .filter(|member| {
+                // Roles are an extension in v12, named after the platform.
+                let role = member["role"]
+                    .as_str()
+                    .or_else(|| member["qq.role"].as_str());
+                matches!(role, Some("owner" | "admin"))
+            })
+            .filter_map(|member| int(&member["user_id"]))
+            .collect())
+    }
+    async fn member_name(&self, group: i64, uin: i64) -> Result<String> {
+        let member = self
+            .call(
+                "get_group_member_info",
+                json!({ "group_id": self.id(group), "user_id": self.id(uin) }),
+            )
+            .await?;
+        let (card, nickname) = match self.version {
+            OneBotVersion::V11 => (&member["card"], &member["nickname"]),
+            OneBotVersion::V12 => (&member["user_displayname"], &member["user_name"]),
+        };
+        let card = card.as_str().unwrap_or_default();
+        let nickname = nickname.as_str().unwrap_or_default();
+        Ok(if card.is_empty() {
+            nickname.to_string()
+        } else {
+            format!("{} ({})", card, nickname)
+        })
+    }
+    async fn group_list(&self) -> Result<Vec<(i64, String)>> {
+        let groups = self.call("get_group_list", json!({})).await?;
+        let groups: Vec<_> = groups
+            .as_array()
+            .context("group list is not a list")?
+            .iter()
+            .filter_map(|group| {
+                let code = int(&group["group_id"])?;
+                let name = group["group_name"].as_str().unwrap_or_default();
+                Some((code, name.to_string()))
+            })
+            .collect();
+        self.group_names.lock().extend(groups.iter().cloned());
+        Ok(groups)
+    }
+    async fn solve_friend_request(&self, msg_seq: i64, _: i64, accept: bool) -> Result<()> {
+        if self.version == OneBotVersion::V12 {
+            bail!("friend requests are not part of OneBot v12");
+        }
+        let flag = i32::try_from(msg_seq)
+            .ok()
+            .and_then(|seq| self.requests.lock().id(seq))
+            .with_context(|| format!("unknown friend request {}", msg_seq))?;
+        self.call(
+            "set_friend_add_request",
+            json!({ "flag": flag, "approve": accept }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{chain, Ids};
+
+    #[test]
+    fn ids_map_both_ways_and_forget_the_oldest() {
+        let mut ids = Ids::default();
+        let first = ids.number("a");
+        assert_eq!(ids.number("a"), first);
+        assert_eq!(ids.id(first).as_deref(), Some("a"));
+        for id in 0..super::IDS_CAPACITY {
+            ids.number(&id.to_string());
+        }
+        assert_eq!(ids.id(first), None);
+        assert_eq!(ids.numbers.len(), super::IDS_CAPACITY);
+    }
+
+    #[test]
+    fn segments_of_both_versions_become_chains() {
+        let v11 = chain(&json!([
+            { "type": "at", "data": { "qq": "42" } },
+            { "type": "text", "data": { "text": " hello" } },
+        ]));
+        let v12 = chain(&json!([
+            { "type": "mention", "data": { "user_id": "42" } },
+            { "type": "text", "data": { "text": " hello" } },
+        ]));
+        assert_eq!(v11.to_string(), v12.to_string());
+        assert!(v11.to_string().ends_with(" hello"));
+    }
+}
@@ -0,0 +1,336 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Result;
+use dptree::di::DependencyMap;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use proc_qq::re_exports::ricq;
+use proc_qq::re_exports::ricq::device::Device;
+use proc_qq::re_exports::ricq::handler::DefaultHandler;
+use proc_qq::re_exports::ricq::structs::{
+    FriendMessage, GroupLeave, GroupMessage, GroupMessageRecall, NewFriendRequest, NewMember,
+};
+use proc_qq::re_exports::ricq::version::ANDROID_WATCH;
+use proc_qq::{
+    ConnectedAndOnlineEvent, DisconnectedAndOfflineEvent, FriendMessageEvent, GroupLeaveEvent,
+    GroupMessageEvent, GroupMessageRecallEvent, NewFriendRequestEvent, NewMemberEvent,
+};
+use reqwest::header::AUTHORIZATION;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+
+use crate::client::Client;
+use crate::config::{OneBotConfig, OneBotVersion};
+use crate::dp_helper::{dispatch, EVHandler, UpdateKind};
+use crate::onebot::{chain, int, OneBot};
+
+/// What a OneBot event translates to. Events no handler uses are not translated.
+enum Update {
+    GroupMessage(GroupMessage),
+    FriendMessage(FriendMessage),
+    GroupMessageRecall(GroupMessageRecall),
+    NewMember(NewMember),
+    GroupLeave(GroupLeave),
+    NewFriendRequest(NewFriendRequest),
+}
+
+/// Stands in for the ricq client events carry, which handlers never use. Built once, since each
+/// build generates a device.
+static PLACEHOLDER: Lazy<Arc<ricq::Client>> = Lazy::new(|| {
+    Arc::new(ricq::Client::new(
+        Device::random(),
+        &ANDROID_WATCH,
+        DefaultHandler,
+    ))
+});
+
+/// Receive events of the implementation until the connection drops, dispatching them to the
+/// handlers like those of the built-in client.
+pub async fn run(config: &OneBotConfig, deps: DependencyMap, handler: EVHandler) -> Result<()> {
+    let mut request = config.websocket.as_str().into_client_request()?;
+    if let Some(token) = &config.token {
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
+    }
+    let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+    let onebot = Arc::new(OneBot::new(config));
+    let uin = onebot.login().await?;
+    info!(uin, websocket = config.websocket, "connected to OneBot");
+    let client: Client = onebot.clone();
+    let result = serve(socket, &onebot, client.clone(), &deps, &handler).await;
+    // However the session ended, the account is offline now.
+    let offline = DisconnectedAndOfflineEvent {
+        client: PLACEHOLDER.clone(),
+    };
+    let kind = UpdateKind::DisconnectedAndOffline;
+    if let Err(e) = dispatch(&deps, &handler, kind, client, offline).await {
+        warn!(?e, "failed to handle OneBot disconnect");
+    }
+    result
+}
+
+/// Dispatch the login, then every event until the connection drops.
+async fn serve(
+    mut socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    onebot: &OneBot,
+    client: Client,
+    deps: &DependencyMap,
+    handler: &EVHandler,
+) -> Result<()> {
+    let online = ConnectedAndOnlineEvent {
+        client: PLACEHOLDER.clone(),
+    };
+    dispatch(
+        deps,
+        handler,
+        UpdateKind::ConnectedAndOnline,
+        client.clone(),
+        online,
+    )
+    .await?;
+
+    loop {
+        let text = match socket.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(frame))) => {
+                debug!(?frame, "OneBot closed the connection");
+                return Ok(());
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(()),
+        };
+        let event: Value = match serde_json::from_str(&text) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(?e, "unexpected OneBot event");
+                continue;
+            }
+        };
+        let Some(update) = translate(onebot, &event).await else {
+            continue;
+        };
+        let client = client.clone();
+        let rq_client = PLACEHOLDER.clone();
+        let result = match update {
+            Update::GroupMessage(inner) => {
+                let event = GroupMessageEvent {
+                    client: rq_client,
+                    inner,
+                };
+                dispatch(deps, handler, UpdateKind::GroupMessage, client, event).await
+            }
+            Update::FriendMessage(inner) => {
+                let event = FriendMessageEvent {
+                    client: rq_client,
+                    inner,
+                };
+                dispatch(deps, handler, UpdateKind::FriendMessage, client, event).await
+            }
+            Update::GroupMessageRecall(inner) => {
+                let event = GroupMessageRecallEvent {
+                    client: rq_client,
+                    inner,
+                };
+                let kind = UpdateKind::GroupMessageRecall;
+                dispatch(deps, handler, kind, client, event).await
+            }
+            Update::NewMember(inner) => {
+                let event = NewMemberEvent {
+                    client: rq_client,
+                    inner,
+                };
+                dispatch(deps, handler, UpdateKind::NewMember, client, event).await
+            }
+            Update::GroupLeave(inner) => {
+                let event = GroupLeaveEvent {
+                    client: rq_client,
+                    inner,
+                };
+                dispatch(deps, handler, UpdateKind::GroupLeave, client, event).await
+            }
+            Update::NewFriendRequest(inner) => {
+                let event = NewFriendRequestEvent {
+                    client: rq_client,
+                    inner,
+                };
+                let kind = UpdateKind::NewFriendRequest;
+                dispatch(deps, handler, kind, client, event).await
+            }
+        };
+        if let Err(e) = result {
+            warn!(?e, "failed to handle OneBot event");
+        }
+    }
+}
+
+/// Translate an event of either protocol version. Field names differ between them, but not in a
+/// way that makes one version's events mistakable for the other's.
+async fn translate(onebot: &OneBot, event: &Value) -> Option<Update> {
+    let version = onebot.version;
+    let (kind, detail) = match version {
+        OneBotVersion::V11 => {
+            let kind = event["post_type"].as_str()?;
+            let detail = match kind {
+                "message" => "message_type",
+                "notice" => "notice_type",
+                "request" => "request_type",
+                _ => return None,
+            };
+            (kind, event[detail].as_str()?)
+        }
+        OneBotVersion::V12 => (event["type"].as_str()?, event["detail_type"].as_str()?),
+    };
+    let time = event["time"].as_f64().unwrap_or_default() as i32;
+    let group_code = int(&event["group_id"]);
+    let user = int(&event["user_id"]);
+    Some(match (kind, detail) {
+        ("message", "group") => {
+            let group_code = group_code?;
+            let elements = chain(&event["message"]);
+            if elements.0.is_empty() {
+                return None;
+            }
+            Update::GroupMessage(GroupMessage {
+                seqs: vec![onebot.message_seq(&event["message_id"])?],
+                rands: vec![0],
+                group_code,
+                group_name: onebot.group_name(group_code).await,
+                group_card: event["sender"]["card"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                time,
+                from_uin: user?,
+                elements,
+            })
+        }
+        // Temporary sessions through a group are v11 private messages of another sub type.
+        ("message", "private")
+            if version == OneBotVersion::V12 || event["sub_type"] == "friend" =>
+        {
+            let elements = chain(&event["message"]);
+            if elements.0.is_empty() {
+                return None;
+            }
+            Update::FriendMessage(FriendMessage {
+                seqs: vec![onebot.message_seq(&event["message_id"])?],
+                rands: vec![0],
+                target: onebot.uin.load(Ordering::Relaxed),
+                time,
+                from_uin: user?,
+                from_nick: event["sender"]["nickname"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                elements,
+            })
+        }
+        ("notice", "group_recall" | "group_message_delete") => {
+            Update::GroupMessageRecall(GroupMessageRecall {
+                msg_seq: onebot.message_seq(&event["message_id"])?,
+                group_code: group_code?,
+                operator_uin: int(&event["operator_id"]).unwrap_or_default(),
+                author_uin: user?,
+                time,
+            })
+        }
+        ("notice", "group_increase" | "group_member_increase") => Update::NewMember(NewMember {
+            group_code: group_code?,
+            member_uin: user?,
+        }),
+        ("notice", "group_decrease" | "group_member_decrease") => Update::GroupLeave(GroupLeave {
+            group_code: group_code?,
+            member_uin: user?,
+            operator_uin: int(&event["operator_id"]),
+        }),
+        ("request", "friend") => Update::NewFriendRequest(NewFriendRequest {
+            msg_seq: onebot.friend_request_seq(event["flag"].as_str()?),
+            message: event["comment"].as_str().unwrap_or_default().to_string(),
+            req_uin: user?,
+            req_nick: String::new(),
+        }),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{translate, Update};
+    use crate::config::{OneBotConfig, OneBotVersion};
+    use crate::onebot::OneBot;
+
+    fn onebot(version: OneBotVersion) -> OneBot {
+        OneBot::new(&OneBotConfig {
+            version,
+            websocket: "ws://127.0.0.1:6700".to_string(),
+            // Nothing listens here, so group names fall back to the code.
+            api: "http://127.0.0.1:1".to_string(),
+            token: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn messages_and_recalls_share_seqs() {
+        let onebot = onebot(OneBotVersion::V12);
+        let message = json!({
+            "type": "message",
+            "detail_type": "group",
+            "message_id": "abc",
+            "group_id": "100",
+            "user_id": "2",
+            "time": 1.5,
+            "message": [{ "type": "text", "data": { "text": "hello" } }],
+        });
+        let Some(Update::GroupMessage(message)) =
+            translate(&onebot, &message).await else {
+            panic!("not a group message");
+        };
+        assert_eq!(message.group_code, 100);
+        assert_eq!(message.from_uin, 2);
+        assert_eq!(message.group_name, "100");
+
+        let recall = json!({
+            "type": "notice",
+            "detail_type": "group_message_delete",
+            "message_id": "abc",
+            "group_id": "100",
+            "user_id": "2",
+            "operator_id": "2",
+        });
+        let Some(Update::GroupMessageRecall(recall)) =
+            translate(&onebot, &recall).await else {
+            panic!("not a recall");
+        };
+        assert_eq!(recall.msg_seq, message.seqs[0]);
+    }
+
+    #[tokio::test]
+    async fn v11_temporary_sessions_are_ignored() {
+        let onebot = onebot(OneBotVersion::V11);
+        let mut message = json!({
+            "post_type": "message",
+            "message_type": "private",
+            "sub_type": "group",
+            "message_id": 1,
+            "user_id": 2,
+            "message": [{ "type": "text", "data": { "text": "hello" } }],
+            "sender": { "nickname": "Bob" },
+        });
+        assert!(translate(&onebot, &message).await.is_none());
+        message["sub_type"] = json!("friend");
+        let Some(Update::FriendMessage(message)) =
+            translate(&onebot, &message).await else {
+            panic!("not a friend message");
+        };
+        assert_eq!(message.from_nick, "Bob");
+    }
+}
@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use anyhow::{bail, Result};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use proc_qq::re_exports::bytes::Bytes;
+use proc_qq::ShowQR;
+use tracing::info;
+
+use crate::config::{
+    FileConfig, MailgunConfig, QrDelivery, SmtpConfig, SmtpSecurity, WebhookConfig,
+};
+
+const SUBJECT: &str = "IM Bridge - QR Code";
+const BODY: &str = "Please scan the qr code to login";
+
+async fn mailgun(config: &MailgunConfig, png: Bytes) -> Result<()> {
+    let form = reqwest::multipart::Form::new()
+        .text("from", format!("IM Bridge <mailgun@{}>", config.domain))
+        .text("to", config.to.clone())
+        .text("subject", SUBJECT)
+        .text("text", BODY)
+        .part(
+            "attachment",
+            reqwest::multipart::Part::bytes(png.to_vec()).file_name("qrcode.png"),
+        );
+    let resp = reqwest::Client::new()
+        .post(&format!(
+            "https://api.mailgun.net/v3/{}/messages",
+            config.domain
+        ))
+        .basic_auth("api", Some(&config.apikey))
+        .multipart(form)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    info!(resp, "QR code sent");
+    Ok(())
+}
+
+async fn smtp(config: &SmtpConfig, png: Bytes) -> Result<()> {
+    let message = Message::builder()
+        .from(config.from.parse()?)
+        .to(config.to.parse()?)
+        .subject(SUBJECT)
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(BODY.to_string()))
+                .singlepart(
+                    Attachment::new("qrcode.png".to_string())
+                        .body(png.to_vec(), ContentType::parse("image/png")?),
+                ),
+        )?;
+    let mut transport = match config.security {
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpSecurity::StartTls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        }
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    };
+    if let Some(port) = config.port {
+        transport = transport.port(port);
+    }
+    match (&config.username, &config.password) {
+        (Some(username), Some(password)) => {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        (None, None) => {}
+        _ => bail!("SMTP username and password must be set together"),
+    }
+    let resp = transport.build().send(message).await?;
+    info!(code = %resp.code(), "QR code sent");
+    Ok(())
+}
+
+async fn webhook(config: &WebhookConfig, png: Bytes) -> Result<()> {
+    let mut req = reqwest::Client::new()
+        .post(&config.url)
+        .header(reqwest::header::CONTENT_TYPE, "image/png");
+    for (name, value) in &config.headers {
+        req = req.header(name, value);
+    }
+    let resp = req.body(png).send().await?.error_for_status()?;
+    info!(status = %resp.status(), "QR code sent");
+    Ok(())
+}
+
+async fn file(config: &FileConfig, png: Bytes) -> Result<()> {
+    tokio::fs::write(&config.path, png).await?;
+    info!(path = %config.path.display(), "QR code written");
+    Ok(())
+}
+
+async fn deliver(delivery: &QrDelivery, png: Bytes) -> Result<()> {
+    match delivery {
+        QrDelivery::Console => bail!("console delivery is left to proc_qq"),
+        QrDelivery::Mailgun(config) => mailgun(config, png).await,
+        QrDelivery::Smtp(config) => smtp(config, png).await,
+        QrDelivery::Webhook(config) => webhook(config, png).await,
+        QrDelivery::File(config) => file(config, png).await,
+    }
+}
+
+pub fn show_qr(delivery: &QrDelivery) -> ShowQR {
+    if matches!(delivery, QrDelivery::Console) {
+        return ShowQR::PrintToConsole;
+    }
+    let delivery = delivery.clone();
+    ShowQR::Custom(Box::pin(
+        move |png: Bytes| -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+            let delivery = delivery.clone();
+            Box::pin(async move { deliver(&delivery, png).await })
+        },
+    ))
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+
+use crate::db::Group;
+
+/// Index of an account in the configured account list. Each client gets its own as a dependency.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AccountIndex(pub usize);
+
+/// Login state of the QQ clients, shared between the event handlers and the HTTP server.
+#[derive(Debug, Clone)]
+pub struct BotStatus(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    accounts: RwLock<Vec<AccountStatus>>,
+    last_event: Mutex<Option<Instant>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccountStatus {
+    pub online: bool,
+    /// Unknown until the account has logged in once.
+    pub uin: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusSnapshot {
+    /// Every configured account is logged in.
+    pub online: bool,
+    pub accounts: Vec<AccountStatus>,
+    /// Seconds since the last update was received from QQ.
+    pub last_event_secs: Option<u64>,
+}
+
+impl BotStatus {
+    pub fn new(accounts: usize) -> Self {
+        Self(Arc::new(Inner {
+            accounts: RwLock::new(vec![AccountStatus::default(); accounts]),
+            last_event: Mutex::new(None),
+        }))
+    }
+    pub fn set_online(&self, account: AccountIndex, uin: i64) {
+        self.0.accounts.write()[account.0] = AccountStatus {
+            online: true,
+            uin: Some(uin),
+        };
+    }
+    /// Mark the account offline, returning its uin if it has ever logged in.
+    pub fn set_offline(&self, account: AccountIndex) -> Option<i64> {
+        let mut accounts = self.0.accounts.write();
+        accounts[account.0].online = false;
+        accounts[account.0].uin
+    }
+    pub fn record_event(&self) {
+        *self.0.last_event.lock() = Some(Instant::now());
+    }
+    pub fn since_last_event(&self) -> Option<Duration> {
+        self.0.last_event.lock().map(|at| at.elapsed())
+    }
+    pub fn snapshot(&self) -> StatusSnapshot {
+        let accounts = self.0.accounts.read().clone();
+        StatusSnapshot {
+            online: !accounts.is_empty() && accounts.iter().all(|account| account.online),
+            accounts,
+            last_event_secs: self.since_last_event().map(|since| since.as_secs()),
+        }
+    }
+}
+
+const RECENT_FAILURES: usize = 50;
+
+/// Delivery counters and the most recent failures of the forwarder.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardStats(Arc<StatsInner>);
+
+#[derive(Debug, Default)]
+struct StatsInner {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    recent_failures: Mutex<VecDeque<ForwardFailure>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ForwardFailure {
+    pub at: Instant,
+    pub source: Group,
+    pub target: Group,
+    pub error: String,
+}
+
+impl ForwardStats {
+    pub fn record_delivered(&self) {
+        self.0.delivered.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn record_failure(&self, source: Group, target: Group, error: &anyhow::Error) {
+        self.0.failed.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.0.recent_failures.lock();
+        if recent.len() == RECENT_FAILURES {
+            recent.pop_back();
+        }
+        recent.push_front(ForwardFailure {
+            at: Instant::now(),
+            source,
+            target,
+            error: format!("{:#}", error),
+        });
+    }
+    pub fn delivered(&self) -> u64 {
+        self.0.delivered.load(Ordering::Relaxed)
+    }
+    pub fn failed(&self) -> u64 {
+        self.0.failed.load(Ordering::Relaxed)
+    }
+    /// Most recent failures first.
+    pub fn recent_failures(&self) -> Vec<ForwardFailure> {
+        self.0.recent_failures.lock().iter().cloned().collect()
+    }
+}